@@ -0,0 +1,466 @@
+use crate::error::ArithmeticError;
+use crate::shell::Shell;
+use crate::variables::Key;
+
+/// How deeply variables whose values are themselves expressions may refer to each other
+const MAX_DEPTH: usize = 100;
+
+/// Operator spellings, longest first so that matching is greedy
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=", "%=",
+    "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "?",
+    ":", "=", ",", "(", ")",
+];
+
+const ASSIGNMENT_OPERATORS: &[&str] = &["=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|="];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    /// A variable, or an array element with the text of its subscript
+    Name(String, Option<String>),
+    Operator(&'static str),
+}
+
+/// Length of the `[subscript]` at the start of `text`, brackets included
+fn subscript_len(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 1 => return Some(i + 1),
+            ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, ArithmeticError> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' {
+            // Numbers may contain letters, `#`, `@` and `_`, as in `0x1f` or `64#A_`
+            let is_number = c.is_ascii_digit();
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || (is_number && (c == '#' || c == '@'))))
+                .unwrap_or(rest.len());
+            let text = rest[..len].to_string();
+            if is_number {
+                tokens.push(Token::Number(text));
+                len
+            } else if rest[len..].starts_with('[') {
+                let subscript_len = subscript_len(&rest[len..]).ok_or_else(|| ArithmeticError::Syntax(rest.to_string()))?;
+                let subscript = rest[len + 1..len + subscript_len - 1].to_string();
+                tokens.push(Token::Name(text, Some(subscript)));
+                len + subscript_len
+            } else {
+                tokens.push(Token::Name(text, None));
+                len
+            }
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Operator(op));
+            op.len()
+        } else {
+            return Err(ArithmeticError::Syntax(rest.to_string()));
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses an integer constant: decimal, octal with a leading `0`, hexadecimal with
+/// `0x`, or `base#digits` for bases 2 to 64
+fn parse_number(text: &str) -> Result<i64, ArithmeticError> {
+    let invalid = || ArithmeticError::InvalidNumber(text.to_string());
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        let base: u32 = base.parse().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base) {
+            return Err(invalid());
+        }
+        (base, digits)
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, hex)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+/// Applies a binary operator
+fn apply(op: &str, left: i64, right: i64) -> Result<i64, ArithmeticError> {
+    Ok(match op {
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" | "%" if right == 0 => return Err(ArithmeticError::DivisionByZero),
+        "/" => left.wrapping_div(right),
+        "%" => left.wrapping_rem(right),
+        "**" if right < 0 => return Err(ArithmeticError::NegativeExponent),
+        "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "<" => i64::from(left < right),
+        ">" => i64::from(left > right),
+        "<=" => i64::from(left <= right),
+        ">=" => i64::from(left >= right),
+        "==" => i64::from(left == right),
+        "!=" => i64::from(left != right),
+        "&" => left & right,
+        "^" => left ^ right,
+        "|" => left | right,
+        _ => unreachable!("not a binary operator: {}", op),
+    })
+}
+
+/// Binary operators from lowest to highest precedence; `**` is handled separately
+/// because it is right-associative
+const BINARY_LEVELS: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Recursive-descent evaluator. While `skip` is set (the unused side of `&&`, `||`
+/// or `?:`) the expression is parsed but variables are not assigned
+struct Evaluator<'a> {
+    shell: &'a mut Shell,
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+    skip: bool,
+}
+
+impl Evaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// Consumes the operator `op` if it comes next
+    fn accept(&mut self, op: &str) -> bool {
+        if self.peek_operator() == Some(op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ArithmeticError> {
+        if self.accept(op) {
+            return Ok(());
+        }
+        Err(self.unexpected())
+    }
+
+    /// Syntax error pointing at the rest of the expression
+    fn unexpected(&self) -> ArithmeticError {
+        let rest: Vec<String> = self.tokens[self.pos.min(self.tokens.len())..]
+            .iter()
+            .map(|token| match token {
+                Token::Number(text) | Token::Name(text, None) => text.clone(),
+                Token::Name(name, Some(subscript)) => format!("{}[{}]", name, subscript),
+                Token::Operator(op) => op.to_string(),
+            })
+            .collect();
+        if rest.is_empty() {
+            ArithmeticError::MissingOperand
+        } else {
+            ArithmeticError::Syntax(rest.join(" "))
+        }
+    }
+
+    /// The element a subscript names, evaluated once so that `a[i++]++` steps `i`
+    /// only once. Nothing is evaluated while skipping
+    fn key(&mut self, name: &str, subscript: Option<&str>) -> Result<Option<Key>, ArithmeticError> {
+        match subscript {
+            Some(subscript) if !self.skip => Ok(Some(self.shell.element_key(name, subscript)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn variable(&mut self, name: &str, key: Option<&Key>) -> Result<i64, ArithmeticError> {
+        let value = match key {
+            Some(key) => self.shell.variables.element(name, key).map(str::to_string),
+            None => self.shell.lookup_variable(name),
+        };
+        let value = value.unwrap_or_default();
+        if value.trim().is_empty() {
+            return Ok(0);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(ArithmeticError::RecursionLimit);
+        }
+        evaluate(self.shell, &value, self.depth + 1)
+    }
+
+    fn assign(&mut self, name: &str, key: Option<Key>, value: i64) -> Result<(), ArithmeticError> {
+        if self.skip {
+            return Ok(());
+        }
+        match key {
+            Some(key) => self.shell.variables.set_element(name, key, value.to_string(), false)?,
+            None => self.shell.variables.set(name, value.to_string())?,
+        }
+        Ok(())
+    }
+
+    fn comma(&mut self) -> Result<i64, ArithmeticError> {
+        let mut value = self.assignment()?;
+        while self.accept(",") {
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64, ArithmeticError> {
+        if let (Some(Token::Name(name, subscript)), Some(Token::Operator(op))) =
+            (self.peek(), self.tokens.get(self.pos + 1))
+        {
+            if ASSIGNMENT_OPERATORS.contains(op) {
+                let (name, subscript, op) = (name.clone(), subscript.clone(), *op);
+                self.pos += 2;
+                let key = self.key(&name, subscript.as_deref())?;
+                let right = self.assignment()?;
+                let value = match op {
+                    "=" => right,
+                    _ if self.skip => 0,
+                    _ => apply(&op[..op.len() - 1], self.variable(&name, key.as_ref())?, right)?,
+                };
+                self.assign(&name, key, value)?;
+                return Ok(value);
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<i64, ArithmeticError> {
+        let condition = self.logical_or()?;
+        if !self.accept("?") {
+            return Ok(condition);
+        }
+        let skip = self.skip;
+        self.skip = skip || condition == 0;
+        let then = self.comma()?;
+        self.expect(":")?;
+        self.skip = skip || condition != 0;
+        let otherwise = self.conditional()?;
+        self.skip = skip;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    fn logical_or(&mut self) -> Result<i64, ArithmeticError> {
+        let mut value = self.logical_and()?;
+        while self.accept("||") {
+            let skip = self.skip;
+            self.skip = skip || value != 0;
+            let right = self.logical_and()?;
+            self.skip = skip;
+            value = i64::from(value != 0 || right != 0);
+        }
+        Ok(value)
+    }
+
+    fn logical_and(&mut self) -> Result<i64, ArithmeticError> {
+        let mut value = self.binary(0)?;
+        while self.accept("&&") {
+            let skip = self.skip;
+            self.skip = skip || value == 0;
+            let right = self.binary(0)?;
+            self.skip = skip;
+            value = i64::from(value != 0 && right != 0);
+        }
+        Ok(value)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, ArithmeticError> {
+        let Some(operators) = BINARY_LEVELS.get(level) else {
+            return self.power();
+        };
+        let mut value = self.binary(level + 1)?;
+        while let Some(op) = self.peek_operator().filter(|op| operators.contains(op)) {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            value = if self.skip { 0 } else { apply(op, value, right)? };
+        }
+        Ok(value)
+    }
+
+    fn power(&mut self) -> Result<i64, ArithmeticError> {
+        let base = self.unary()?;
+        if !self.accept("**") {
+            return Ok(base);
+        }
+        let exponent = self.power()?;
+        if self.skip {
+            return Ok(0);
+        }
+        apply("**", base, exponent)
+    }
+
+    fn unary(&mut self) -> Result<i64, ArithmeticError> {
+        let Some(op) = self.peek_operator() else {
+            return self.postfix();
+        };
+        match op {
+            "++" | "--" => {
+                self.pos += 1;
+                let Some(Token::Name(name, subscript)) = self.peek().cloned() else {
+                    return Err(self.unexpected());
+                };
+                self.pos += 1;
+                let key = self.key(&name, subscript.as_deref())?;
+                let value = self.variable(&name, key.as_ref())?.wrapping_add(if op == "++" { 1 } else { -1 });
+                self.assign(&name, key, value)?;
+                Ok(value)
+            }
+            "-" | "+" | "!" | "~" => {
+                self.pos += 1;
+                let value = self.unary()?;
+                Ok(match op {
+                    "-" => value.wrapping_neg(),
+                    "+" => value,
+                    "!" => i64::from(value == 0),
+                    _ => !value,
+                })
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<i64, ArithmeticError> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(ArithmeticError::MissingOperand);
+        };
+        self.pos += 1;
+        match token {
+            Token::Number(text) => parse_number(&text),
+            Token::Name(name, subscript) => {
+                let key = self.key(&name, subscript.as_deref())?;
+                let value = self.variable(&name, key.as_ref())?;
+                if let Some(op @ ("++" | "--")) = self.peek_operator() {
+                    self.pos += 1;
+                    self.assign(&name, key, value.wrapping_add(if op == "++" { 1 } else { -1 }))?;
+                }
+                Ok(value)
+            }
+            Token::Operator("(") => {
+                let value = self.comma()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Token::Operator(_) => {
+                self.pos -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+}
+
+fn evaluate(shell: &mut Shell, expr: &str, depth: usize) -> Result<i64, ArithmeticError> {
+    let tokens = tokenize(expr)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut evaluator = Evaluator { shell, tokens, pos: 0, depth, skip: false };
+    let value = evaluator.comma()?;
+    if evaluator.pos < evaluator.tokens.len() {
+        return Err(evaluator.unexpected());
+    }
+    Ok(value)
+}
+
+impl Shell {
+    /// Evaluates an arithmetic expression whose `$` expansions have already been
+    /// performed; variables and array elements are read and assigned by name
+    pub fn evaluate_arithmetic(&mut self, expr: &str) -> Result<i64, ArithmeticError> {
+        evaluate(self, expr, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, ArithmeticError> {
+        Shell::for_tests().evaluate_arithmetic(expr)
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("5 - 3 - 1").unwrap(), 1);
+        assert_eq!(eval("2 ** 3 ** 2").unwrap(), 512);
+        assert_eq!(eval("-2 ** 2").unwrap(), 4);
+        assert_eq!(eval("1 << 2 + 1").unwrap(), 8);
+        assert_eq!(eval("7 & 3 == 3").unwrap(), 1);
+        assert_eq!(eval("1 || 0 && 0").unwrap(), 1);
+        assert_eq!(eval("0 ? 2 : 1 ? 3 : 4").unwrap(), 3);
+        assert_eq!(eval("x = 2, x *= 3, x + 1").unwrap(), 7);
+    }
+
+    #[test]
+    fn overflow_wraps_around() {
+        assert_eq!(eval("9223372036854775807 + 1").unwrap(), i64::MIN);
+        assert_eq!(eval("-9223372036854775807 - 2").unwrap(), i64::MAX);
+        assert_eq!(eval("2 ** 64").unwrap(), 0);
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1").unwrap(), i64::MIN);
+    }
+
+    #[test]
+    fn number_bases() {
+        assert_eq!(eval("010").unwrap(), 8);
+        assert_eq!(eval("0x1f").unwrap(), 31);
+        assert_eq!(eval("2#101").unwrap(), 5);
+        assert_eq!(eval("64#_").unwrap(), 63);
+        let error = eval("08").unwrap_err();
+        assert!(matches!(&error, ArithmeticError::InvalidNumber(token) if token == "08"));
+        assert_eq!(error.to_string(), "value too great for base (error token is \"08\")");
+        assert!(matches!(eval("65#1"), Err(ArithmeticError::InvalidNumber(_))));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(eval("2 ** -1"), Err(ArithmeticError::NegativeExponent)));
+        assert!(matches!(eval("1 / 0"), Err(ArithmeticError::DivisionByZero)));
+        assert!(matches!(eval("1 +"), Err(ArithmeticError::MissingOperand)));
+        assert!(matches!(eval("1 2"), Err(ArithmeticError::Syntax(_))));
+        // The unused side of `&&` is not evaluated, so it cannot fail
+        assert_eq!(eval("0 && 1 / 0").unwrap(), 0);
+    }
+}
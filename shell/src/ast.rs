@@ -0,0 +1,285 @@
+use std::sync::Arc;
+
+/// Byte range of a syntax node within the parsed input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// Returns a span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// A single shell word, kept as the sequence of differently quoted pieces it was written as
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WordPart {
+    /// Unquoted text
+    Literal(String),
+    /// Text protected by single quotes, `$'...'` or a backslash
+    Quoted(String),
+    /// `"..."`; the inner parts are interpreted in double-quote context
+    DoubleQuoted(Vec<WordPart>),
+    /// `$NAME` or `${...}`
+    Param(ParamExpansion),
+    /// `$(list)` or `` `list` ``
+    CommandSubst(List),
+    /// `$((expression))`
+    Arithmetic(Vec<WordPart>),
+    /// `(word...)` assigned to an array, as in `NAME=(a b c)`
+    Array(Vec<Word>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamExpansion {
+    pub name: String,
+    /// Subscript of `${NAME[index]}`
+    pub index: Option<Word>,
+    pub op: ParamOp,
+    /// Written as `${...}`. Brace expansion can still add to the name of a bare
+    /// `$NAME`, so that `$x{a,b}` stands for `$xa $xb`
+    pub braced: bool,
+}
+
+/// What to do with a parameter's value. `colon` distinguishes `${NAME:-word}`, which
+/// treats an empty value like an unset one, from `${NAME-word}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamOp {
+    /// `$NAME` / `${NAME}`
+    Value,
+    /// `${#NAME}`
+    Length,
+    /// `${!NAME[@]}`, the indices or keys of an array
+    Keys,
+    /// `${NAME:offset}` and `${NAME:offset:length}`
+    Slice { offset: Word, length: Option<Word> },
+    /// `${NAME:-word}`
+    Default { colon: bool, word: Word },
+    /// `${NAME:=word}`
+    Assign { colon: bool, word: Word },
+    /// `${NAME:?word}`
+    Error { colon: bool, word: Word },
+    /// `${NAME:+word}`
+    Alternative { colon: bool, word: Word },
+    /// `${NAME#pattern}` and `${NAME##pattern}`
+    RemovePrefix { longest: bool, pattern: Word },
+    /// `${NAME%pattern}` and `${NAME%%pattern}`
+    RemoveSuffix { longest: bool, pattern: Word },
+    /// `${NAME/pattern/replacement}` and its `//`, `/#` and `/%` forms
+    Replace { mode: ReplaceMode, pattern: Word, replacement: Word },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceMode {
+    First,
+    All,
+    Prefix,
+    Suffix,
+}
+
+impl Word {
+    /// Returns the text of a word made only of unquoted characters.
+    /// Used to recognise reserved words such as `{` and `}`
+    pub fn as_literal(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [WordPart::Literal(text)] => Some(text),
+            _ => None,
+        }
+    }
+
+    /// True if the word contains a command substitution outside `${...}`
+    pub fn has_command_subst(&self) -> bool {
+        fn any(parts: &[WordPart]) -> bool {
+            parts.iter().any(|part| match part {
+                WordPart::CommandSubst(_) => true,
+                WordPart::DoubleQuoted(inner) => any(inner),
+                WordPart::Array(words) => words.iter().any(Word::has_command_subst),
+                _ => false,
+            })
+        }
+        any(&self.parts)
+    }
+}
+
+/// A sequence of and-or lists separated by `;`, `&` or newlines
+#[derive(Debug, Clone, PartialEq)]
+pub struct List {
+    pub items: Vec<ListItem>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListItem {
+    pub and_or: AndOr,
+    /// Set when the item was terminated by `&`
+    pub background: bool,
+    /// The item as written, which the job table shows for background jobs
+    pub text: String,
+}
+
+/// Pipelines joined by `&&` and `||`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(AndOrOp, Pipeline)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AndOrOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    /// Set when the pipeline is preceded by `!`
+    pub negated: bool,
+    pub commands: Vec<Command>,
+    pub span: Span,
+    /// The pipeline as it was written, which names it as a job
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    Compound(CompoundCommand, Vec<Redirect>),
+    /// `name() compound-command` or `function name compound-command`
+    Function(Arc<FunctionDef>),
+}
+
+/// A function definition; shared between the syntax tree and the shell's function table
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    pub body: CompoundCommand,
+    /// Redirections applied every time the function runs
+    pub redirects: Vec<Redirect>,
+    /// The definition as it was written, printed by `type`
+    pub source: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+    pub span: Span,
+}
+
+/// `NAME=value` written in front of a command (or on its own)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    /// Subscript of `NAME[index]=value`
+    pub index: Option<Word>,
+    /// `NAME+=value` appends to the variable
+    pub append: bool,
+    /// The value, which is a single `WordPart::Array` for `NAME=(...)`
+    pub value: Word,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    /// Explicit file descriptor written before the operator, e.g. the `2` in `2>err`
+    pub fd: Option<u32>,
+    pub kind: RedirectKind,
+    pub target: Word,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectKind {
+    /// `<`
+    Input,
+    /// `>`
+    Output,
+    /// `>>`
+    Append,
+    /// `>|`
+    Clobber,
+    /// `<>`
+    ReadWrite,
+    /// `<&`
+    DupInput,
+    /// `>&`
+    DupOutput,
+    /// `&>`
+    OutputAll,
+    /// `&>>`
+    AppendAll,
+    /// `<<` and `<<-`; the target is the body of the here-document
+    HereDoc,
+    /// `<<<`
+    HereString,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCommand {
+    /// `{ list; }`
+    BraceGroup(List),
+    /// `( list )`
+    Subshell(List),
+    /// `(( expression ))`
+    Arithmetic(Word),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If { branches: Vec<(List, List)>, else_branch: Option<List> },
+    /// `while list; do list; done`, or `until` when `until` is set
+    While { condition: List, body: List, until: bool },
+    /// `for name [in words]; do list; done`; without `in` the positional
+    /// parameters are used
+    For { name: String, words: Option<Vec<Word>>, body: List },
+    /// `for (( init; condition; step )); do list; done`
+    ArithmeticFor { init: Word, condition: Word, step: Word, body: List },
+    /// `case word in [(]pattern[|pattern]...) list ;; ... esac`
+    Case { word: Word, items: Vec<CaseItem> },
+    /// `[[ expression ]]`
+    Conditional(CondExpr),
+}
+
+/// An expression inside `[[ ]]`. Its words are expanded without field splitting or
+/// pathname expansion
+#[derive(Debug, Clone, PartialEq)]
+pub enum CondExpr {
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+    Not(Box<CondExpr>),
+    /// `-f word` and the other unary tests
+    Unary(String, Word),
+    /// `word == pattern`, `word =~ regex`, `word -lt word` and the other binary tests
+    Binary(Word, String, Word),
+    /// A word on its own, true if it expands to a non-empty string
+    Word(Word),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: CaseTerminator,
+}
+
+/// What happens after the body of a matching `case` item has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseTerminator {
+    /// `;;`: the `case` command is done
+    Break,
+    /// `;&`: the next item's body runs as well
+    FallThrough,
+    /// `;;&`: the following patterns are tested too
+    Continue,
+}
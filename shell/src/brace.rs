@@ -0,0 +1,236 @@
+use crate::ast::{Word, WordPart};
+use crate::parser::is_valid_name;
+
+/// A word broken into unquoted characters, which may take part in brace expansion,
+/// and the other parts, which are carried along untouched
+#[derive(Debug, Clone)]
+enum Piece {
+    Char(char),
+    Part(WordPart),
+}
+
+/// Performs brace expansion on a word: `a{b,c}d` becomes `abd acd`, and `{1..3}` or
+/// `{a..e..2}` become sequences. Words without a valid brace expression are returned
+/// unchanged
+pub fn expand_braces(word: &Word) -> Vec<Word> {
+    let mut pieces = Vec::new();
+    for part in &word.parts {
+        match part {
+            WordPart::Literal(text) => pieces.extend(text.chars().map(Piece::Char)),
+            part => pieces.push(Piece::Part(part.clone())),
+        }
+    }
+    if !pieces.iter().any(|piece| matches!(piece, Piece::Char('{'))) {
+        return vec![word.clone()];
+    }
+
+    expand(&pieces)
+        .into_iter()
+        .map(|pieces| Word { parts: to_parts(pieces), span: word.span })
+        .collect()
+}
+
+fn to_parts(pieces: Vec<Piece>) -> Vec<WordPart> {
+    let mut parts = Vec::new();
+    for piece in pieces {
+        match (piece, parts.last_mut()) {
+            (Piece::Char(c), Some(WordPart::Literal(text))) => text.push(c),
+            // Expansion happens after brace expansion, so a bare `$NAME` takes in
+            // the name characters that now follow it
+            (Piece::Char(c), Some(WordPart::Param(param)))
+                if !param.braced && (c.is_ascii_alphanumeric() || c == '_') && is_valid_name(&param.name) =>
+            {
+                param.name.push(c)
+            }
+            (Piece::Char(c), _) => parts.push(WordPart::Literal(c.to_string())),
+            (Piece::Part(part), _) => parts.push(part),
+        }
+    }
+    parts
+}
+
+fn expand(pieces: &[Piece]) -> Vec<Vec<Piece>> {
+    let mut search = 0;
+    while let Some(open) = find_char(pieces, search, '{') {
+        search = open + 1;
+        let Some((close, commas)) = find_close(pieces, open) else {
+            continue;
+        };
+
+        let alternatives = if commas.is_empty() {
+            match sequence(&pieces[open + 1..close]) {
+                Some(items) => items.into_iter().map(|item| item.chars().map(Piece::Char).collect()).collect(),
+                None => continue,
+            }
+        } else {
+            let mut alternatives = Vec::new();
+            let mut start = open + 1;
+            for end in commas.into_iter().chain(std::iter::once(close)) {
+                alternatives.extend(expand(&pieces[start..end]));
+                start = end + 1;
+            }
+            alternatives
+        };
+
+        let preamble = &pieces[..open];
+        let postscripts = expand(&pieces[close + 1..]);
+        let mut words = Vec::new();
+        for alternative in &alternatives {
+            for postscript in &postscripts {
+                let mut word = preamble.to_vec();
+                word.extend(alternative.iter().cloned());
+                word.extend(postscript.iter().cloned());
+                words.push(word);
+            }
+        }
+        return words;
+    }
+    vec![pieces.to_vec()]
+}
+
+fn find_char(pieces: &[Piece], from: usize, c: char) -> Option<usize> {
+    (from..pieces.len()).find(|&i| matches!(pieces[i], Piece::Char(p) if p == c))
+}
+
+/// Finds the `}` matching the `{` at `open` and the commas directly inside the pair
+fn find_close(pieces: &[Piece], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    for (i, piece) in pieces.iter().enumerate().skip(open + 1) {
+        match piece {
+            Piece::Char('{') => depth += 1,
+            Piece::Char('}') if depth == 0 => return Some((i, commas)),
+            Piece::Char('}') => depth -= 1,
+            Piece::Char(',') if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expands the body of a `{start..end[..step]}` sequence of integers or letters
+fn sequence(body: &[Piece]) -> Option<Vec<String>> {
+    let text = body
+        .iter()
+        .map(|piece| match piece {
+            Piece::Char(c) => Some(*c),
+            Piece::Part(_) => None,
+        })
+        .collect::<Option<String>>()?;
+    let bounds: Vec<&str> = text.split("..").collect();
+    let (start, end, step) = match bounds.as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1);
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // A leading zero on either end pads every number to the same width
+        let padded = |s: &str| s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        return Some(
+            range(first, last, step)
+                .map(|n| {
+                    if n < 0 {
+                        format!("-{:0width$}", n.unsigned_abs(), width = width.saturating_sub(1))
+                    } else {
+                        format!("{:0width$}", n, width = width)
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    let mut start_chars = start.chars();
+    let mut end_chars = end.chars();
+    match (start_chars.next(), start_chars.next(), end_chars.next(), end_chars.next()) {
+        (Some(first), None, Some(last), None) if first.is_ascii_alphabetic() && last.is_ascii_alphabetic() => Some(
+            range(first as i64, last as i64, step)
+                .map(|c| char::from(c as u8).to_string())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Counts from `first` to `last` inclusive in either direction
+fn range(first: i64, last: i64, step: u64) -> impl Iterator<Item = i64> {
+    let count = first.abs_diff(last) / step + 1;
+    let step = if last < first { -(step as i64) } else { step as i64 };
+    (0..count as i64).map(move |i| first + i * step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_words;
+
+    /// Brace-expands a word and writes each result back as source text
+    fn expand(text: &str) -> Vec<String> {
+        let word = parse_words(text).unwrap().remove(0);
+        expand_braces(&word).iter().map(|word| word.parts.iter().map(source).collect()).collect()
+    }
+
+    fn source(part: &WordPart) -> String {
+        match part {
+            WordPart::Literal(text) => text.clone(),
+            WordPart::Quoted(text) => format!("'{}'", text),
+            WordPart::Param(param) if param.braced => format!("${{{}}}", param.name),
+            WordPart::Param(param) => format!("${}", param.name),
+            part => panic!("unexpected part {:?}", part),
+        }
+    }
+
+    #[test]
+    fn alternatives() {
+        assert_eq!(expand("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand("{a,b{1,2}}"), ["a", "b1", "b2"]);
+        assert_eq!(expand("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand("x{,y}"), ["x", "xy"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expand("{1..3}"), ["1", "2", "3"]);
+        assert_eq!(expand("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expand("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(expand("{01..10..3}"), ["01", "04", "07", "10"]);
+        assert_eq!(expand("{-05..5..5}"), ["-05", "000", "005"]);
+        assert_eq!(expand("{-2..2..2}"), ["-2", "0", "2"]);
+    }
+
+    #[test]
+    fn negative_and_zero_steps_count_towards_the_end() {
+        assert_eq!(expand("{1..5..-2}"), ["1", "3", "5"]);
+        assert_eq!(expand("{5..1..-2}"), ["5", "3", "1"]);
+        assert_eq!(expand("{1..3..0}"), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn words_without_a_valid_brace_expression_are_unchanged() {
+        assert_eq!(expand("{a}"), ["{a}"]);
+        assert_eq!(expand("{}"), ["{}"]);
+        assert_eq!(expand("{a,b"), ["{a,b"]);
+        assert_eq!(expand("{1..a}"), ["{1..a}"]);
+    }
+
+    #[test]
+    fn quoted_braces_and_commas_are_literal() {
+        assert_eq!(expand("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(expand("{a,'b,c'}"), ["a", "'b,c'"]);
+        assert_eq!(expand("{a,b}'{1,2}'"), ["a'{1,2}'", "b'{1,2}'"]);
+    }
+
+    #[test]
+    fn a_bare_parameter_takes_in_the_name_characters_after_it() {
+        assert_eq!(expand("$x{a,b}"), ["$xa", "$xb"]);
+        assert_eq!(expand("${x}{a,b}"), ["${x}a", "${x}b"]);
+        assert_eq!(expand("$x{-,+}"), ["$x-", "$x+"]);
+        // `$1` is a complete name, so the `a` stays a separate part
+        let word = parse_words("$1{a,b}").unwrap().remove(0);
+        assert_eq!(expand_braces(&word)[0].parts.len(), 2);
+        let word = parse_words("$x{a,b}").unwrap().remove(0);
+        assert_eq!(expand_braces(&word)[0].parts.len(), 1);
+    }
+}
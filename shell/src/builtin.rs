@@ -1,143 +1,187 @@
 use std::fs::{self, File};
-use std::io::{self, Write, BufReader, BufRead};
+use std::io::{self, Read, Write, BufReader, BufRead};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::env;
-use chrono;
-use filetime;
-use which;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
 
+use crate::control_flow::Flow;
+use crate::error::{describe_io_error, BuiltinError, BuiltinResult};
+use crate::options::ShellOptions;
+use crate::parser;
 use crate::shell::Shell;
+use crate::traps::Trap;
+use crate::variables::declaration_options;
 
 impl Shell {
-    pub fn cd(&mut self, args: &[String]) -> io::Result<()> {
-        let new_dir = match args.get(0) {
-            Some(path) if path == "~" || path == "$HOME" => self.home_dir.clone(),
-            Some(path) => {
-                let path_buf = PathBuf::from(path);
-                if path_buf.is_absolute() {
-                    path_buf
-                } else {
-                    self.current_dir.join(path_buf)
-                }
+    pub fn cd(&mut self, args: &[String]) -> BuiltinResult {
+        // `~` and `$HOME` have already been expanded, so the word is taken as it is
+        let home;
+        let target = match args.first() {
+            Some(path) => path,
+            None => {
+                home = self.lookup_variable("HOME");
+                home.as_ref().ok_or_else(|| BuiltinError::InvalidArgument("HOME not set".to_string()))?
             }
-            None => self.home_dir.clone(),
         };
+        let path_buf = PathBuf::from(target);
+        let new_dir = if path_buf.is_absolute() { path_buf } else { self.current_dir.join(path_buf) };
 
+        // Only the shell's own directory changes, so that subshells running in other
+        // threads keep theirs; commands are started in `current_dir`
         let normalized_path = self.normalize_path(&new_dir);
-        env::set_current_dir(&normalized_path)?;
-        self.current_dir = env::current_dir()?;
-        Ok(())
+        let shown = target.as_str();
+        let metadata = fs::metadata(&normalized_path).map_err(|e| BuiltinError::path(shown, e))?;
+        if !metadata.is_dir() {
+            return Err(BuiltinError::path(shown, io::Error::other("Not a directory")));
+        }
+        let old_dir = std::mem::replace(&mut self.current_dir, normalized_path);
+        let _ = self.variables.set("OLDPWD", old_dir.to_string_lossy());
+        let _ = self.variables.set("PWD", self.current_dir.to_string_lossy());
+        Ok(0)
     }
 
-    pub fn echo(&mut self, args: &[String]) -> io::Result<()> {
-        println!("{}", args.join(" "));
-        Ok(())
+    pub fn echo(&mut self, args: &[String]) -> BuiltinResult {
+        writeln!(self.stdout(), "{}", args.join(" "))?;
+        Ok(0)
     }
 
-    pub fn pwd(&mut self, _args: &[String]) -> io::Result<()> {
-        println!("{}", self.current_dir.display());
-        Ok(())
+    pub fn pwd(&mut self, _args: &[String]) -> BuiltinResult {
+        writeln!(self.stdout(), "{}", self.current_dir.display())?;
+        Ok(0)
     }
 
-    pub fn clear(&mut self, _args: &[String]) -> io::Result<()> {
+    pub fn clear(&mut self, _args: &[String]) -> BuiltinResult {
         match self.shell_type {
             crate::shell_type::ShellType::Windows => {
                 Command::new("cmd").args(["/C", "cls"]).status()?;
             }
             _ => {
-                print!("\x1B[2J\x1B[1;1H");
-                io::stdout().flush()?;
+                let mut out = self.stdout();
+                write!(out, "\x1B[2J\x1B[1;1H")?;
+                out.flush()?;
             }
         }
-        Ok(())
+        Ok(0)
     }
 
-    pub fn touch(&mut self, args: &[String]) -> io::Result<()> {
+    pub fn touch(&mut self, args: &[String]) -> BuiltinResult {
         if args.is_empty() {
-            eprintln!("touch: missing file operand");
-            return Ok(());
+            return Err(BuiltinError::MissingOperand("file operand"));
         }
-    
+
+        let mut status = 0;
         for file_name in args {
-            let path = Path::new(file_name);
-            if path.exists() {
-                let now = filetime::FileTime::now();
-                if let Err(e) = filetime::set_file_mtime(path, now) {
-                    eprintln!("touch: failed to update times for '{}': {}", file_name, e);
-                }
+            let path = self.resolve_path(file_name);
+            let result = if path.exists() {
+                filetime::set_file_mtime(&path, filetime::FileTime::now())
             } else {
-                if let Err(e) = File::create(path) {
-                    eprintln!("touch: failed to create '{}': {}", file_name, e);
-                }
+                File::create(&path).map(|_| ())
+            };
+            if let Err(e) = result {
+                writeln!(self.stderr(), "touch: {}", BuiltinError::path(file_name, e))?;
+                status = 1;
             }
         }
-        Ok(())
+        Ok(status)
     }
 
-    pub fn cat(&mut self, args: &[String]) -> io::Result<()> {
-        if args.is_empty() {
-            eprintln!("cat: missing file operand");
-            return Ok(());
-        }
-    
-        for file_name in args {
-            let path = Path::new(file_name);
-            if path.exists() {
-                let file = File::open(path)?;
-                let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    println!("{}", line?);
-                }
+    /// Copies each file, or standard input when there are none (or for `-`), to
+    /// standard output
+    pub fn cat(&mut self, args: &[String]) -> BuiltinResult {
+        let stdin_only = ["-".to_string()];
+        let files = if args.is_empty() { &stdin_only[..] } else { args };
+
+        let mut out = self.stdout();
+        let mut status = 0;
+        for file_name in files {
+            let copied = if file_name == "-" {
+                self.copy(&mut self.stdin(), &mut out)
             } else {
-                eprintln!("cat: {}: No such file or directory", file_name);
+                match File::open(self.resolve_path(file_name)) {
+                    Ok(mut file) => self.copy(&mut file, &mut out).map_err(|e| match e {
+                        BuiltinError::Io(e) => BuiltinError::path(file_name, e),
+                        e => e,
+                    }),
+                    Err(e) => Err(BuiltinError::path(file_name, e)),
+                }
+            };
+            match copied {
+                Err(BuiltinError::Interrupted) => return Err(BuiltinError::Interrupted),
+                Err(e) => {
+                    writeln!(self.stderr(), "cat: {}", e)?;
+                    status = 1;
+                }
+                Ok(()) => {}
             }
         }
-        Ok(())
+        Ok(status)
     }
-    
-    pub fn mkdir(&mut self, args: &[String]) -> io::Result<()> {
+
+    /// Copies `reader` to `out` like `io::copy`, stopping if the command is interrupted
+    fn copy(&self, reader: &mut impl Read, out: &mut impl Write) -> Result<(), BuiltinError> {
+        let mut buffer = [0; 8192];
+        loop {
+            self.check_interrupt()?;
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.check_interrupt()?;
+                    return Err(e.into());
+                }
+            };
+            out.write_all(&buffer[..read])?;
+        }
+    }
+
+    pub fn mkdir(&mut self, args: &[String]) -> BuiltinResult {
         if args.is_empty() {
-            eprintln!("mkdir: missing operand");
-            return Ok(());
+            return Err(BuiltinError::MissingOperand("operand"));
         }
-    
+
+        let mut status = 0;
         for dir_name in args {
-            let path = Path::new(dir_name);
-            if let Err(e) = fs::create_dir(path) {
-                eprintln!("mkdir: cannot create directory '{}': {}", dir_name, e);
+            if let Err(e) = fs::create_dir(self.resolve_path(dir_name)) {
+                writeln!(self.stderr(), "mkdir: cannot create directory '{}': {}", dir_name, describe_io_error(&e))?;
+                status = 1;
             }
         }
-        Ok(())
+        Ok(status)
     }
-    
-    pub fn env(&mut self, _args: &[String]) -> io::Result<()> {
-        for (key, value) in &self.env_vars {
-            println!("{}={}", key, value);
+
+    pub fn env(&mut self, _args: &[String]) -> BuiltinResult {
+        let mut out = self.stdout();
+        for (key, value) in self.variables.exported() {
+            writeln!(out, "{}={}", key, value)?;
         }
-        Ok(())
+        Ok(0)
     }
 
-    pub fn which(&mut self, args: &[String]) -> io::Result<()> {
+    pub fn which(&mut self, args: &[String]) -> BuiltinResult {
         if args.is_empty() {
-            println!("which: missing command name");
-            return Ok(());
+            return Err(BuiltinError::MissingOperand("command name"));
         }
 
         let cmd = &args[0];
-        if self.builtins.contains_key(cmd) {
-            println!("{} is a shell builtin", cmd);
+        if let Some(function) = self.functions.get(cmd) {
+            writeln!(self.stdout(), "{} is a function\n{}", cmd, function.source)?;
+        } else if self.builtins.contains_key(cmd) {
+            writeln!(self.stdout(), "{} is a shell builtin", cmd)?;
         } else {
             match which::which(cmd) {
-                Ok(path) => println!("{}", path.display()),
-                Err(_) => println!("{}: command not found", cmd),
+                Ok(path) => writeln!(self.stdout(), "{}", path.display())?,
+                Err(_) => return Err(BuiltinError::NotFound(cmd.clone())),
             }
         }
-        Ok(())
+        Ok(0)
     }
 
-    pub fn ls(&mut self, args: &[String]) -> io::Result<()> {
-        let path = args.get(0).map(|s| Path::new(s)).unwrap_or(&self.current_dir);
+    pub fn ls(&mut self, args: &[String]) -> BuiltinResult {
+        let shown = args.first().map(Path::new).unwrap_or(&self.current_dir);
+        let path = &self.resolve_path(shown);
+        let mut out = self.stdout();
         
         match self.shell_type {
             crate::shell_type::ShellType::Windows => {
@@ -147,22 +191,13 @@ impl Shell {
                     .status()?;
             }
             _ => {
-                println!("{} {} {:>8} {:>19} {}", 
-                    "Type",
-                    "Perms",
-                    "Size",
-                    "Modified",
-                    "Name"
-                );
-                println!("{} {} {:>8} {:>19} {}", 
-                    "----",
-                    "-----",
-                    "----",
-                    "-------------------",
-                    "----"
-                );
+                writeln!(out, "Type Perms {:>8} {:>19} Name", "Size", "Modified")?;
+                writeln!(out, "---- ----- {:>8} {:>19} ----", "----", "-------------------")?;
     
-                for entry in fs::read_dir(path)? {
+                let entries = fs::read_dir(path)
+                    .map_err(|e| BuiltinError::path(shown.display().to_string(), e))?;
+                for entry in entries {
+                    self.check_interrupt()?;
                     let entry = entry?;
                     let metadata = entry.metadata()?;
                     let file_type = if metadata.is_dir() { "d" } else { "-" };
@@ -179,65 +214,321 @@ impl Shell {
                     let formatted_time = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
                     
                     let name = entry.file_name();
-                    println!("{:4} {:5} {:8} {} {}", 
+                    writeln!(out, "{:4} {:5} {:8} {} {}", 
                         file_type, 
                         permissions, 
                         size, 
                         formatted_time,
                         name.to_string_lossy()
-                    );
+                    )?;
                 }
             }
         }
-        Ok(())
+        Ok(0)
     }
 
-    pub fn type_cmd(&mut self, args: &[String]) -> io::Result<()> {
+    pub fn type_cmd(&mut self, args: &[String]) -> BuiltinResult {
         if args.is_empty() {
-            println!("type: missing command name");
-            return Ok(());
+            return Err(BuiltinError::MissingOperand("command name"));
         }
 
         let cmd = &args[0];
-        if self.builtins.contains_key(cmd) {
-            println!("{} is a shell builtin", cmd);
+        if let Some(function) = self.functions.get(cmd) {
+            writeln!(self.stdout(), "{} is a function\n{}", cmd, function.source)?;
+        } else if self.builtins.contains_key(cmd) {
+            writeln!(self.stdout(), "{} is a shell builtin", cmd)?;
         } else {
             match which::which(cmd) {
-                Ok(path) => println!("{} is {}", cmd, path.display()),
-                Err(_) => println!("{}: not found", cmd),
+                Ok(path) => writeln!(self.stdout(), "{} is {}", cmd, path.display())?,
+                Err(_) => return Err(BuiltinError::NotFound(cmd.clone())),
             }
         }
-        Ok(())
+        Ok(0)
     }
 
-    pub fn history(&mut self, args: &[String]) -> io::Result<()> {
-        let limit = args.get(0)
+    pub fn history(&mut self, args: &[String]) -> BuiltinResult {
+        let limit = args.first()
             .and_then(|s| s.parse::<usize>().ok())
             .unwrap_or(self.history.len());
 
+        let mut out = self.stdout();
         for (i, cmd) in self.history.iter().rev().take(limit).rev().enumerate() {
-            println!("{:5} {}", i + 1, cmd);
+            writeln!(out, "{:5} {}", i + 1, cmd)?;
         }
-        Ok(())
+        Ok(0)
     }
 
-    pub fn source(&mut self, args: &[String]) -> io::Result<()> {
+    /// Runs the commands in a file in the current shell; the status is that of the last
+    /// command executed
+    pub fn source(&mut self, args: &[String]) -> BuiltinResult {
         if args.is_empty() {
-            println!("source: missing file operand");
-            return Ok(());
+            return Err(BuiltinError::MissingOperand("file operand"));
         }
 
-        let path = PathBuf::from(&args[0]);
-        let file = File::open(path)?;
+        let file = File::open(self.resolve_path(&args[0])).map_err(|e| BuiltinError::path(&args[0], e))?;
         let reader = BufReader::new(file);
 
+        // Extra arguments become the positional parameters while the file runs
+        let saved_params = if args.len() > 1 {
+            Some(std::mem::replace(&mut self.positional_params, args[1..].to_vec()))
+        } else {
+            None
+        };
+
+        self.last_status = 0;
+        self.source_depth += 1;
+        let mut result = Ok(());
+        let mut command = String::new();
         for line in reader.lines() {
-            let line = line?;
-            if !line.trim().is_empty() && !line.trim().starts_with('#') {
-                self.execute_command(&line)?;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    result = Err(BuiltinError::path(&args[0], e));
+                    break;
+                }
+            };
+            if command.is_empty() && (line.trim().is_empty() || line.trim().starts_with('#')) {
+                continue;
+            }
+            if self.options.verbose {
+                let _ = writeln!(self.stderr(), "{}", line);
+            }
+            // Commands such as here-documents span several lines
+            command.push_str(&line);
+            command.push('\n');
+            if parser::needs_more_input(&command) {
+                continue;
+            }
+            if let Err(e) = self.execute_command(&std::mem::take(&mut command)) {
+                result = Err(e.into());
+                break;
+            }
+            if self.flow.is_some() || self.interrupted() {
+                break;
+            }
+        }
+        if result.is_ok() && !command.is_empty() && self.flow.is_none() && !self.interrupted() {
+            result = self.execute_command(&command).map_err(BuiltinError::from);
+        }
+        self.run_trap(Trap::Return);
+        self.source_depth -= 1;
+
+        if let Some(params) = saved_params {
+            self.positional_params = params;
+        }
+        // `return` leaves the file with its own status
+        if let Some(Flow::Return(status)) = self.flow {
+            self.flow = None;
+            return result.map(|()| status);
+        }
+        result.map(|()| self.last_status)
+    }
+
+    /// `break [n]` leaves the `n` innermost loops
+    pub fn break_cmd(&mut self, args: &[String]) -> BuiltinResult {
+        let count = self.loop_count("break", args)?;
+        if count > 0 {
+            self.flow = Some(Flow::Break(count));
+        }
+        Ok(0)
+    }
+
+    /// `continue [n]` starts the next iteration of the `n`th innermost loop
+    pub fn continue_cmd(&mut self, args: &[String]) -> BuiltinResult {
+        let count = self.loop_count("continue", args)?;
+        if count > 0 {
+            self.flow = Some(Flow::Continue(count));
+        }
+        Ok(0)
+    }
+
+    /// Parses the loop count of `break` or `continue`, limited to the number of
+    /// enclosing loops; 0 means there is no loop to leave
+    fn loop_count(&mut self, name: &str, args: &[String]) -> Result<usize, BuiltinError> {
+        let count = match args.first() {
+            None => 1,
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(n) if n >= 1 => n as usize,
+                Ok(_) => return Err(BuiltinError::InvalidArgument(format!("{}: loop count out of range", arg))),
+                Err(_) => return Err(BuiltinError::Usage(format!("{}: numeric argument required", arg))),
+            },
+        };
+        if self.loop_depth == 0 {
+            writeln!(self.stderr(), "{}: only meaningful in a `for', `while', or `until' loop", name)?;
+        }
+        Ok(count.min(self.loop_depth))
+    }
+
+    /// `return [n]` leaves the running function or sourced file with status `n`, or
+    /// with the status of the last command
+    pub fn return_cmd(&mut self, args: &[String]) -> BuiltinResult {
+        if self.local_scopes.is_empty() && self.source_depth == 0 {
+            return Err(BuiltinError::InvalidArgument(
+                "can only `return' from a function or sourced script".to_string(),
+            ));
+        }
+        let status = match args.first() {
+            None => self.last_status,
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(n) => (n & 0xff) as i32,
+                Err(_) => return Err(BuiltinError::Usage(format!("{}: numeric argument required", arg))),
+            },
+        };
+        self.flow = Some(Flow::Return(status));
+        Ok(status)
+    }
+
+    /// `exit [n]` leaves the shell with status `n`, or with the status of the last
+    /// command. Functions and sourced files unwind first; in a subshell only the
+    /// subshell exits
+    pub fn exit_cmd(&mut self, args: &[String]) -> BuiltinResult {
+        let status = match args.first() {
+            None => self.last_status,
+            Some(arg) => match arg.parse::<i64>() {
+                Ok(n) => (n & 0xff) as i32,
+                Err(_) => {
+                    self.flow = Some(Flow::Exit(2));
+                    return Err(BuiltinError::Usage(format!("{}: numeric argument required", arg)));
+                }
+            },
+        };
+        if !self.in_subshell {
+            println!("Exit command received, shutting down...");
+        }
+        self.flow = Some(Flow::Exit(status));
+        Ok(status)
+    }
+
+    /// `local [-aArx] name[=value]...` gives the running function its own copy of each
+    /// variable, restored when the function returns. Without arguments the function's
+    /// local variables are listed
+    pub fn local(&mut self, args: &[String]) -> BuiltinResult {
+        let Some(scope) = self.local_scopes.last() else {
+            return Err(BuiltinError::InvalidArgument("can only be used in a function".to_string()));
+        };
+        if args.is_empty() {
+            let mut names: Vec<&String> = scope.keys().collect();
+            names.sort();
+            let mut out = self.stdout();
+            for name in names {
+                if let Some(value) = self.variables.get(name) {
+                    writeln!(out, "{}={}", name, value)?;
+                }
+            }
+            return Ok(0);
+        }
+        let (attributes, _, names) = declaration_options(args, true)?;
+        Ok(self.declare_variables("local", names, attributes)?)
+    }
+
+    /// `set -o name` / `set +o name` turn shell options on and off, as do the letters
+    /// of `set -euxCfv` / `set +euxCfv`; `-o` or `+o` on their own list the options.
+    /// Arguments after the options, or after `--`, become the positional parameters,
+    /// and without arguments the variables are listed
+    pub fn set(&mut self, args: &[String]) -> BuiltinResult {
+        if args.is_empty() {
+            self.print_variables()?;
+            return Ok(0);
+        }
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--" => {
+                    self.positional_params = args.cloned().collect();
+                    break;
+                }
+                param if param.len() < 2 || !param.starts_with(['-', '+']) => {
+                    self.positional_params = std::iter::once(arg).chain(args).cloned().collect();
+                    break;
+                }
+                _ => {}
+            }
+            let enable = arg.starts_with('-');
+            for letter in arg[1..].chars() {
+                let name = match letter {
+                    'o' => match args.next() {
+                        Some(name) if ShellOptions::SET_NAMES.contains(&name.as_str()) => name.as_str(),
+                        Some(name) => return Err(BuiltinError::InvalidArgument(format!("{}: invalid option name", name))),
+                        None => {
+                            self.print_options(enable)?;
+                            continue;
+                        }
+                    },
+                    _ => match ShellOptions::LETTERS.iter().find(|&&(option, _)| option == letter) {
+                        Some(&(_, name)) => name,
+                        None => {
+                            return Err(BuiltinError::InvalidArgument(format!("{}{}: invalid option", &arg[..1], letter)))
+                        }
+                    },
+                };
+                self.options.set(name, enable);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Lists the options as `set -o` does, or as reusable commands for `set +o`
+    fn print_options(&self, readable: bool) -> io::Result<()> {
+        let mut out = self.stdout();
+        for &name in ShellOptions::SET_NAMES {
+            let on = self.options.get(name).unwrap_or(false);
+            if readable {
+                writeln!(out, "{:<15}\t{}", name, if on { "on" } else { "off" })?;
+            } else {
+                writeln!(out, "set {}o {}", if on { '-' } else { '+' }, name)?;
             }
         }
         Ok(())
     }
+
+    /// `shopt -s name...` / `shopt -u name...` turn options on and off, `shopt -p`
+    /// prints them as commands and `shopt name...` reports whether they are all on;
+    /// `-q` only sets the status. With `-o` the names are those of `set -o`
+    pub fn shopt(&mut self, args: &[String]) -> BuiltinResult {
+        let mut flags = String::new();
+        let mut names = args;
+        while let Some(flag) = names.first().and_then(|arg| arg.strip_prefix('-')) {
+            if flag.is_empty() || !flag.chars().all(|letter| "opqsu".contains(letter)) {
+                return Err(BuiltinError::Usage("usage: shopt [-pqsu] [-o] [optname ...]".to_string()));
+            }
+            flags.push_str(flag);
+            names = &names[1..];
+        }
+        let known = if flags.contains('o') { ShellOptions::SET_NAMES } else { ShellOptions::SHOPT_NAMES };
+        if let Some(name) = names.iter().find(|name| !known.contains(&name.as_str())) {
+            return Err(BuiltinError::InvalidArgument(format!("{}: invalid shell option name", name)));
+        }
+
+        if flags.contains('s') || flags.contains('u') {
+            if flags.contains('s') && flags.contains('u') {
+                return Err(BuiltinError::Usage("cannot set and unset shell options simultaneously".to_string()));
+            }
+            for name in names {
+                self.options.set(name, flags.contains('s'));
+            }
+            return Ok(0);
+        }
+
+        let listed: Vec<&str> = if names.is_empty() {
+            known.to_vec()
+        } else {
+            names.iter().map(String::as_str).collect()
+        };
+        let mut out = self.stdout();
+        let mut all_on = true;
+        for name in listed {
+            let on = self.options.get(name).unwrap_or(false);
+            all_on &= on;
+            if flags.contains('q') {
+                continue;
+            }
+            if flags.contains('p') {
+                let option = if flags.contains('o') { " -o" } else { "" };
+                writeln!(out, "shopt {}{} {}", if on { "-s" } else { "-u" }, option, name)?;
+            } else {
+                writeln!(out, "{:<15}\t{}", name, if on { "on" } else { "off" })?;
+            }
+        }
+        Ok(if all_on || names.is_empty() { 0 } else { 1 })
+    }
 }
-             
\ No newline at end of file
@@ -1,125 +1,520 @@
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 // use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use crate::shell::Shell;
+use std::process::{Child, Command, ExitStatus};
+use std::thread::{self, JoinHandle};
+use crate::ast::{self, AndOr, AndOrOp, CompoundCommand, List, Pipeline, Redirect, SimpleCommand, Word, WordPart};
+use crate::control_flow::Flow;
+use crate::error::BuiltinError;
+use crate::parser;
+use crate::redirection::{pipe, FdTable, Output};
+use crate::shell::{BuiltinFn, Shell};
 use crate::shell_type::ShellType;
+use crate::traps::Trap;
+use crate::utils::quote_if_needed;
+use crate::variables::Value;
+
+/// Converts a process exit status into the number reported by `$?`
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Reports a command that could not be run on `stderr` and returns the exit status for it
+fn command_failure(name: &str, error: &io::Error, mut stderr: Output) -> i32 {
+    let (message, status) = match error.kind() {
+        io::ErrorKind::NotFound => ("command not found".to_string(), 127),
+        io::ErrorKind::PermissionDenied => (error.to_string(), 126),
+        _ => (error.to_string(), 1),
+    };
+    let _ = writeln!(stderr, "{}: {}", name, message);
+    status
+}
+
+/// Stack size of the threads that run shell code, which needs room for deeply
+/// recursive functions
+pub const THREAD_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+pub fn spawn_thread(f: impl FnOnce() -> i32 + Send + 'static) -> io::Result<JoinHandle<i32>> {
+    thread::Builder::new().stack_size(THREAD_STACK_SIZE).spawn(f)
+}
+
+/// A pipeline stage that has been started
+pub enum Stage {
+    Process(Child),
+    /// A builtin or compound command running on a copy of the shell
+    Thread(JoinHandle<i32>),
+    Finished(i32),
+}
+
+/// What checking on a stage found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageState {
+    Running,
+    Stopped,
+    /// Finished with this exit status
+    Done(i32),
+}
+
+impl Stage {
+    /// Waits for the stage to finish and returns its exit status
+    pub fn wait(self) -> io::Result<i32> {
+        Ok(match self {
+            Stage::Process(mut child) => exit_code(child.wait()?),
+            Stage::Thread(handle) => handle.join().unwrap_or(1),
+            Stage::Finished(status) => status,
+        })
+    }
+
+    /// Checks on the stage, blocking until it finishes or stops if `block` is set.
+    /// A process that has stopped is reported once, when it stops
+    pub fn poll(&mut self, block: bool) -> io::Result<StageState> {
+        let state = match self {
+            #[cfg(unix)]
+            Stage::Process(child) => crate::jobs::wait_process(child.id(), block)?,
+            #[cfg(not(unix))]
+            Stage::Process(child) if block => StageState::Done(exit_code(child.wait()?)),
+            #[cfg(not(unix))]
+            Stage::Process(child) => child.try_wait()?.map_or(StageState::Running, |status| StageState::Done(exit_code(status))),
+            Stage::Thread(handle) if !block && !handle.is_finished() => StageState::Running,
+            Stage::Thread(_) => {
+                let Stage::Thread(handle) = std::mem::replace(self, Stage::Finished(1)) else {
+                    unreachable!()
+                };
+                StageState::Done(handle.join().unwrap_or(1))
+            }
+            Stage::Finished(status) => StageState::Done(*status),
+        };
+        if let StageState::Done(status) = state {
+            *self = Stage::Finished(status);
+        }
+        Ok(state)
+    }
+
+    /// Process ID of an external command
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            Stage::Process(child) => Some(child.id()),
+            _ => None,
+        }
+    }
+}
 
 impl Shell {
-    pub fn parse_command(&self, input: &str) -> Vec<String> {
-        let mut tokens = Vec::new();
-        let mut current_token = String::new();
-        let mut in_quotes = false;
-        let mut escaped = false;
-
-        for c in input.chars() {
-            match (c, in_quotes, escaped) {
-                ('\\', _, false) => escaped = true,
-                ('"', _, true) => {
-                    current_token.push('"');
-                    escaped = false;
-                }
-                ('"', _, false) => {
-                    in_quotes = !in_quotes;
-                }
-                ('|', false, false) => {
-                    if !current_token.is_empty() {
-                        tokens.push(current_token);
-                        current_token = String::new();
-                    }
-                    tokens.push("|".to_string());
-                }
-                (' ', false, false) => {
-                    if !current_token.is_empty() {
-                        tokens.push(current_token.clone());
-                        current_token.clear();
-                    }
-                }
-                (c, _, true) => {
-                    current_token.push(c);
-                    escaped = false;
-                }
-                (c, _, false) => current_token.push(c),
+    /// Parses `input` and executes the resulting command list; its exit status is left
+    /// in `last_status`. A syntax error gives status 2 and an error that aborts the
+    /// list, such as a failed expansion, status 1, and either counts as a failed command
+    pub fn execute_command(&mut self, input: &str) -> io::Result<()> {
+        let program = match parser::parse(input) {
+            Ok(program) => program,
+            Err(e) => {
+                self.command_failed(2);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
             }
+        };
+        if let Err(e) = self.execute_list(&program) {
+            self.command_failed(1);
+            return Err(e);
         }
+        Ok(())
+    }
 
-        if !current_token.is_empty() {
-            tokens.push(current_token);
+    /// Sets the status of a command that could not run and treats it as failed
+    fn command_failed(&mut self, status: i32) {
+        self.last_status = status;
+        if self.condition_depth == 0 {
+            self.report_failure(status);
         }
+    }
 
-        tokens
+    /// Runs the `ERR` trap for a failed command, and ends the shell under `set -e`
+    fn report_failure(&mut self, status: i32) {
+        self.run_command_trap(Trap::Err);
+        if self.options.errexit && self.flow.is_none() {
+            self.flow = Some(Flow::Exit(status));
+        }
     }
 
-    pub fn check_redirection(&self, args: &[String]) -> (Vec<String>, Option<String>) {
-        let mut new_args = Vec::new();
-        let mut output_file = None;
-        let mut i = 0;
+    /// Runs every item of the list in order and returns the status of the last one.
+    /// Errors are reserved for failures that abort the whole list, such as `${VAR:?}`
+    pub fn execute_list(&mut self, list: &List) -> io::Result<i32> {
+        let mut status = 0;
+        for item in &list.items {
+            if item.background {
+                self.start_background(&item.and_or, &item.text)?;
+                status = 0;
+                continue;
+            }
+            status = self.execute_and_or(&item.and_or)?;
+            self.run_pending_traps();
+            // A `break` or `continue`, or Ctrl-C, skips the rest of the list
+            if self.flow.is_some() || self.interrupted() {
+                break;
+            }
+        }
+        Ok(status)
+    }
 
-        while i < args.len() {
-            if args[i] == ">" && i + 1 < args.len() {
-                output_file = Some(args[i + 1].clone());
-                i += 2;
-            } else {
-                new_args.push(args[i].clone());
-                i += 1;
+    pub fn execute_and_or(&mut self, and_or: &AndOr) -> io::Result<i32> {
+        let mut status = self.execute_tested(&and_or.first, !and_or.rest.is_empty())?;
+        // Only the last pipeline can fail; those before it are tested by `&&` or `||`
+        let mut ran_last = and_or.rest.is_empty();
+        for (i, (op, pipeline)) in and_or.rest.iter().enumerate() {
+            ran_last = false;
+            if self.flow.is_some() || self.interrupted() {
+                break;
+            }
+            let run = match op {
+                AndOrOp::And => status == 0,
+                AndOrOp::Or => status != 0,
+            };
+            if run {
+                status = self.execute_tested(pipeline, i + 1 < and_or.rest.len())?;
+                ran_last = true;
             }
         }
+        let last = and_or.rest.last().map_or(&and_or.first, |(_, pipeline)| pipeline);
+        if status != 0 && ran_last && self.counts_as_failure(last) {
+            self.report_failure(status);
+        }
+        Ok(status)
+    }
 
-        (new_args, output_file)
+    /// Runs a pipeline; when it is tested by `&&`, `||` or `!`, the commands it runs
+    /// are exempt from `ERR` and `set -e` as in a condition
+    fn execute_tested(&mut self, pipeline: &Pipeline, tested: bool) -> io::Result<i32> {
+        if !tested && !pipeline.negated {
+            return self.execute_pipeline(pipeline);
+        }
+        self.condition_depth += 1;
+        let result = self.execute_pipeline(pipeline);
+        self.condition_depth -= 1;
+        result
     }
 
-    pub fn execute_command(&mut self, input: &str) -> io::Result<()> {
-        let tokens = self.parse_command(input);
-        if tokens.is_empty() {
-            return Ok(());
+    /// Whether a pipeline that returned non-zero is a failed command. It is not when
+    /// negated or tested by a condition, nor when it ends in a compound command such as
+    /// `if`, whose own commands have already failed or been tested
+    pub fn counts_as_failure(&self, pipeline: &Pipeline) -> bool {
+        if pipeline.negated || self.condition_depth > 0 {
+            return false;
         }
+        match pipeline.commands.last() {
+            Some(ast::Command::Compound(compound, _)) => matches!(
+                compound,
+                CompoundCommand::Subshell(_) | CompoundCommand::Arithmetic(_) | CompoundCommand::Conditional(_)
+            ),
+            _ => true,
+        }
+    }
 
-        // Split commands by pipe
-        let mut commands: Vec<Vec<String>> = Vec::new();
-        let mut current_command = Vec::new();
+    /// Runs a pipeline, applies `!` and `pipefail` and records the exit status in
+    /// `last_status` and the status of each stage in `PIPESTATUS`
+    fn execute_pipeline(&mut self, pipeline: &Pipeline) -> io::Result<i32> {
+        let statuses = match pipeline.commands.as_slice() {
+            [command] => vec![self.execute_single_command(command)?],
+            // Under job control the stages form a job that Ctrl-Z can stop
+            commands if self.terminal.is_some() => {
+                self.run_foreground(&pipeline.text, |shell| shell.start_pipeline(commands))?
+            }
+            commands => self.execute_piped_commands(commands)?,
+        };
 
-        for token in tokens {
-            if token == "|" {
-                if !current_command.is_empty() {
-                    commands.push(current_command);
-                    current_command = Vec::new();
-                }
-            } else {
-                current_command.push(token);
+        let last = statuses.last().copied().unwrap_or(0);
+        let mut status = if self.options.pipefail {
+            statuses.iter().rev().copied().find(|&status| status != 0).unwrap_or(0)
+        } else {
+            last
+        };
+        if pipeline.negated {
+            status = i32::from(status == 0);
+        }
+        self.variables.declare("PIPESTATUS").value = Some(Value::list(statuses.iter().map(i32::to_string)));
+        self.last_status = status;
+        Ok(status)
+    }
+
+    fn execute_single_command(&mut self, command: &ast::Command) -> io::Result<i32> {
+        match command {
+            ast::Command::Simple(simple) => self.execute_simple_command(simple),
+            ast::Command::Compound(compound, redirects) => self.execute_redirected(compound, redirects),
+            ast::Command::Function(function) => {
+                self.functions.insert(function.name.clone(), function.clone());
+                Ok(0)
             }
         }
-        if !current_command.is_empty() {
-            commands.push(current_command);
+    }
+
+    /// Runs a compound command with its redirections applied to every command inside it
+    pub fn execute_redirected(&mut self, compound: &CompoundCommand, redirects: &[Redirect]) -> io::Result<i32> {
+        if redirects.is_empty() {
+            return self.execute_compound_command(compound);
         }
+        let targets = self.expand_redirect_targets(redirects)?;
+        let fds = match self.open_redirects(redirects, &targets, &self.fds) {
+            Ok(fds) => fds,
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "{}", e);
+                return Ok(1);
+            }
+        };
+        let saved = std::mem::replace(&mut self.fds, fds);
+        let result = self.execute_compound_command(compound);
+        self.fds = saved;
+        result
+    }
 
-        // execute the piped commands
-        if commands.len() > 1 {
-            self.execute_piped_commands(&commands)
-        } else {
-            let tokens = &commands[0];
-            if tokens.is_empty() {
-                return Ok(());
+    fn execute_compound_command(&mut self, compound: &CompoundCommand) -> io::Result<i32> {
+        match compound {
+            CompoundCommand::BraceGroup(body) => self.execute_list(body),
+            CompoundCommand::Arithmetic(expr) => self.run_arithmetic(expr),
+            // Changes made by the subshell, including `cd`, stay in its copy of the
+            // shell. Under job control it runs in a thread as a job of its own, so
+            // stopping its processes stops it as a whole
+            CompoundCommand::Subshell(body) if self.terminal.is_some() => {
+                let items: Vec<&str> = body.items.iter().map(|item| item.text.as_str()).collect();
+                let statuses = self.run_foreground(&format!("( {} )", items.join("; ")), |shell| {
+                    let mut sub = shell.thread_copy();
+                    let body = body.clone();
+                    let thread = spawn_thread(move || {
+                        sub.execute_subshell(&body).unwrap_or_else(|e| {
+                            let _ = writeln!(sub.stderr(), "{}", e);
+                            1
+                        })
+                    });
+                    match thread {
+                        Ok(handle) => (vec![Stage::Thread(handle)], Ok(())),
+                        Err(e) => (Vec::new(), Err(e)),
+                    }
+                })?;
+                Ok(statuses.last().copied().unwrap_or(0))
+            }
+            CompoundCommand::Subshell(body) => self.subshell().execute_subshell(body),
+            CompoundCommand::If { branches, else_branch } => self.execute_if(branches, else_branch.as_ref()),
+            CompoundCommand::While { condition, body, until } => self.execute_while(condition, body, *until),
+            CompoundCommand::For { name, words, body } => self.execute_for(name, words.as_deref(), body),
+            CompoundCommand::ArithmeticFor { init, condition, step, body } => {
+                self.execute_arithmetic_for(init, condition, step, body)
+            }
+            CompoundCommand::Case { word, items } => self.execute_case(word, items),
+            CompoundCommand::Conditional(expr) => self.execute_conditional(expr),
+        }
+    }
+
+    /// A copy of the shell to run in another thread. Only the shell itself controls
+    /// the terminal, so the copy leaves job control to it
+    pub fn thread_copy(&self) -> Shell {
+        let mut sub = self.subshell();
+        sub.terminal = None;
+        sub
+    }
+
+    /// A copy of the shell for a subshell. Traps that ignore a signal are kept and the
+    /// others are cleared
+    pub fn subshell(&self) -> Shell {
+        let mut sub = self.clone();
+        sub.traps = self.traps.for_subshell();
+        sub.in_subshell = true;
+        sub
+    }
+
+    /// Runs the body of a subshell, then the `EXIT` trap it set
+    pub fn execute_subshell(&mut self, body: &List) -> io::Result<i32> {
+        let status = self.execute_list(body)?;
+        self.run_trap(Trap::Exit);
+        Ok(status)
+    }
+
+    /// Evaluates an arithmetic command. The status is 0 when the expression is
+    /// non-zero, as a test would be
+    pub fn run_arithmetic(&mut self, expr: &Word) -> io::Result<i32> {
+        let expr = self.expand_word(expr)?;
+        self.trace_command(["((".to_string(), expr.trim().to_string(), "))".to_string()]);
+        match self.evaluate_arithmetic(&expr) {
+            Ok(value) => Ok(i32::from(value == 0)),
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "{}: {}", expr.trim(), e);
+                Ok(1)
             }
+        }
+    }
+
+    /// Runs `list` in a subshell and returns what it writes to standard output, minus
+    /// trailing newlines. `last_status` is set to the status of the list
+    pub fn command_substitution(&mut self, list: &List) -> io::Result<String> {
+        let (mut reader, writer) = pipe()?;
+        let mut subshell = self.thread_copy();
+        subshell.fds.set_file(1, writer);
 
-            let command = &tokens[0];
-            let args = &tokens[1..];
+        let mut output = Vec::new();
+        let (read, status) = thread::scope(|scope| -> io::Result<_> {
+            let handle = thread::Builder::new().stack_size(THREAD_STACK_SIZE).spawn_scoped(scope, move || {
+                subshell.execute_subshell(list).unwrap_or_else(|e| {
+                    let _ = writeln!(subshell.stderr(), "{}", e);
+                    1
+                })
+            })?;
+            let read = reader.read_to_end(&mut output);
+            Ok((read, handle.join().unwrap_or(1)))
+        })?;
+        read?;
+
+        self.last_status = status;
+        let mut output = String::from_utf8_lossy(&output).into_owned();
+        output.truncate(output.trim_end_matches('\n').len());
+        Ok(output)
+    }
+
+    /// Expands the prefix assignments of a command; as these only hold strings, the
+    /// elements of an array list are joined with spaces
+    fn expand_assignments(&mut self, command: &SimpleCommand) -> io::Result<Vec<(String, String)>> {
+        let mut assignments = Vec::new();
+        for assignment in &command.assignments {
+            let value = match assignment.value.parts.as_slice() {
+                [WordPart::Array(elements)] => {
+                    let elements = self.expand_array(elements)?;
+                    elements.into_iter().map(|(_, value)| value).collect::<Vec<_>>().join(" ")
+                }
+                _ => self.expand_assignment_value(&assignment.value)?,
+            };
+            assignments.push((assignment.name.clone(), value));
+        }
+        Ok(assignments)
+    }
 
-            let (args, output_file) = self.check_redirection(args);
+    fn execute_simple_command(&mut self, command: &SimpleCommand) -> io::Result<i32> {
+        self.run_command_trap(Trap::Debug);
+        let tokens = self.expand_words(&command.words)?;
+        let targets = self.expand_redirect_targets(&command.redirects)?;
+        let fds = match self.open_redirects(&command.redirects, &targets, &self.fds) {
+            Ok(fds) => fds,
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "{}", e);
+                return Ok(1);
+            }
+        };
 
-            // First, check if it's a builtin command using the original command name
-            if let Some(builtin) = self.builtins.get(command) {
-                return builtin(self, &args.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        // Without a command name the assignments are made in order, so that each
+        // can use the ones before it
+        let assignments = if tokens.is_empty() { Vec::new() } else { self.expand_assignments(command)? };
+        self.trace_simple_command(&assignments, &tokens);
+        let Some((command, args)) = tokens.split_first() else {
+            let mut failed = false;
+            for assignment in &command.assignments {
+                if let Err(e) = self.assign(assignment)? {
+                    let _ = writeln!(fds.output(2), "{}", e);
+                    failed = true;
+                }
             }
+            // With no command name the status is that of the last command substitution
+            let substituted = command.assignments.iter().any(|a| a.value.has_command_subst());
+            return Ok(if failed { 1 } else if substituted { self.last_status } else { 0 });
+        };
 
-            let (mapped_command, mapped_args) = self.map_command(command, &args);
-            
-            if let Some(builtin) = self.builtins.get(&mapped_command) {
-                return builtin(self, &mapped_args.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        // Assignments before a function or builtin last until it returns; an external
+        // command gets them in its environment only
+        let is_function = self.functions.contains_key(command);
+        let builtin = self.find_builtin(command, args);
+        let temporary = if is_function || builtin.is_some() {
+            self.assign_temporary(&assignments)
+        } else {
+            self.variables.check_assignable(assignments.iter().map(|(name, _)| name.as_str())).map(|()| Vec::new())
+        };
+        let temporary = match temporary {
+            Ok(temporary) => temporary,
+            Err(e) => {
+                let _ = writeln!(fds.output(2), "{}", e);
+                return Ok(1);
             }
+        };
+
+        if let Some(function) = self.functions.get(command).cloned() {
+            let saved = std::mem::replace(&mut self.fds, fds);
+            let result = self.call_function(&function, args);
+            self.fds = saved;
+            self.restore_variables(temporary);
+            return result;
+        }
+
+        if let Some((name, builtin, args)) = builtin {
+            // Builtins write through `self.fds`, so give them the command's descriptors
+            let saved = std::mem::replace(&mut self.fds, fds);
+            let status = self.run_builtin(&name, builtin, &args);
+            self.fds = saved;
+            self.restore_variables(temporary);
+            return Ok(status);
+        }
+
+        let (mapped_command, mapped_args) = self.map_command(command, args);
+        let result = match self.terminal {
+            Some(_) => self
+                .run_foreground(&tokens.join(" "), |shell| {
+                    match shell.spawn_external(&mapped_command, &mapped_args, &assignments, &fds) {
+                        Ok(child) => (vec![Stage::Process(child)], Ok(())),
+                        Err(e) => (Vec::new(), Err(e)),
+                    }
+                })
+                .map(|statuses| statuses.last().copied().unwrap_or(0)),
+            None => self.execute_external_command(&mapped_command, &mapped_args, &assignments, &fds),
+        };
+        match result {
+            Ok(status) => Ok(status),
+            Err(e) => Ok(command_failure(&mapped_command, &e, fds.output(2))),
+        }
+    }
+
+    /// With `xtrace`, prints a line to standard error made of `PS4` and the given words
+    pub fn trace_command(&self, words: impl IntoIterator<Item = String>) {
+        if !self.options.xtrace {
+            return;
+        }
+        let prefix = self.variables.get("PS4").unwrap_or("+ ");
+        let words: Vec<String> = words.into_iter().collect();
+        let _ = writeln!(self.stderr(), "{}{}", prefix, words.join(" "));
+    }
+
+    /// Traces a command with a name as it is about to run, with its prefix assignments
+    fn trace_simple_command(&self, assignments: &[(String, String)], words: &[String]) {
+        if words.is_empty() {
+            return;
+        }
+        let assignments = assignments.iter().map(|(name, value)| format!("{}={}", name, quote_if_needed(value)));
+        self.trace_command(assignments.chain(words.iter().map(|word| quote_if_needed(word))));
+    }
 
-            // Finally, execute as external command
-            self.execute_external_command(&mapped_command, &mapped_args, output_file)
+    /// Looks up the builtin that runs `command`, either directly or through the
+    /// command name mapping of the selected shell type
+    fn find_builtin(&self, command: &str, args: &[String]) -> Option<(String, BuiltinFn, Vec<String>)> {
+        if let Some(&builtin) = self.builtins.get(command) {
+            return Some((command.to_string(), builtin, args.to_vec()));
         }
+        let (mapped_command, mapped_args) = self.map_command(command, args);
+        let &builtin = self.builtins.get(&mapped_command)?;
+        Some((mapped_command, builtin, mapped_args))
+    }
+
+    /// Runs a builtin, reporting any error it returns and converting it to an exit status
+    fn run_builtin(&mut self, name: &str, builtin: BuiltinFn, args: &[String]) -> i32 {
+        let status = match builtin(self, args) {
+            Ok(status) => status,
+            // The reader of a pipe went away; there is nobody to tell
+            Err(BuiltinError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => 1,
+            Err(e @ BuiltinError::Interrupted) => e.exit_code(),
+            // A read cut short by Ctrl-C, as in `head` waiting on the terminal
+            Err(BuiltinError::Io(_)) if self.interrupted() => BuiltinError::Interrupted.exit_code(),
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "{}: {}", name, e);
+                e.exit_code()
+            }
+        };
+        let _ = self.stdout().flush();
+        status
     }
 
     pub fn map_command(&self, command: &str, args: &[String]) -> (String, Vec<String>) {
@@ -161,56 +556,135 @@ impl Shell {
         }
     }
 
-    pub fn execute_piped_commands(&mut self, commands: &[Vec<String>]) -> io::Result<()> {
-        let mut prev_stdout = None;
+    /// Runs the stages of a pipeline with each stage's stdout connected to the next
+    /// stage's stdin; redirections of a stage are applied on top of the pipe.
+    /// Builtins and compound commands run on a copy of the shell in their own thread.
+    /// Every stage is waited for, and their exit statuses are returned in order
+    pub fn execute_piped_commands(&mut self, commands: &[ast::Command]) -> io::Result<Vec<i32>> {
+        let (stages, started) = self.start_pipeline(commands);
+        // Stages already running are reaped even if a later one could not be started
+        let mut statuses = Vec::with_capacity(stages.len());
+        for stage in stages {
+            statuses.push(stage.wait()?);
+        }
+        started.map(|()| statuses)
+    }
 
-        for (i, command) in commands.iter().enumerate() {
-            if command.is_empty() {
-                continue;
-            }
+    /// Starts every stage of a pipeline without waiting for them. If a stage cannot be
+    /// started, the stages before it are returned along with the error
+    pub fn start_pipeline(&mut self, commands: &[ast::Command]) -> (Vec<Stage>, io::Result<()>) {
+        let mut prev_stdout: Option<File> = None;
+        let mut stages = Vec::new();
 
-            let (command_name, args) = self.map_command(&command[0], &command[1..].to_vec());
-            
+        for (i, command) in commands.iter().enumerate() {
             let is_last = i == commands.len() - 1;
-            let mut cmd = Command::new(&command_name);
-            cmd.args(&args);
 
-            if let Some(prev_out) = prev_stdout {
-                cmd.stdin(prev_out);
+            let mut fds = self.fds.clone();
+            if let Some(prev_out) = prev_stdout.take() {
+                fds.set_file(0, prev_out);
             }
-
             if !is_last {
-                cmd.stdout(Stdio::piped());
+                match pipe() {
+                    Ok((reader, writer)) => {
+                        fds.set_file(1, writer);
+                        prev_stdout = Some(reader);
+                    }
+                    Err(e) => return (stages, Err(e)),
+                }
             }
 
-            let output = if is_last {
-                cmd.status()?;
-                None
-            } else {
-                let child = cmd.spawn()?;
-                Some(child.stdout.unwrap())
-            };
+            match self.start_stage(command, fds) {
+                Ok(stage) => stages.push(stage),
+                Err(e) => return (stages, Err(e)),
+            }
+        }
+        (stages, Ok(()))
+    }
+
+    /// Starts one pipeline stage with the given descriptors without waiting for it
+    fn start_stage(&mut self, command: &ast::Command, fds: FdTable) -> io::Result<Stage> {
+        let ast::Command::Simple(simple) = command else {
+            let mut sub = self.thread_copy();
+            sub.fds = fds;
+            let command = command.clone();
+            return Ok(Stage::Thread(spawn_thread(move || {
+                sub.wait_for_job_start();
+                sub.execute_single_command(&command).unwrap_or_else(|e| {
+                    let _ = writeln!(sub.stderr(), "{}", e);
+                    1
+                })
+            })?));
+        };
 
-            prev_stdout = output;
+        let words = self.expand_words(&simple.words)?;
+        let assignments = self.expand_assignments(simple)?;
+        self.trace_simple_command(&assignments, &words);
+        let targets = self.expand_redirect_targets(&simple.redirects)?;
+        let fds = match self.open_redirects(&simple.redirects, &targets, &fds) {
+            Ok(fds) => fds,
+            Err(e) => {
+                let _ = writeln!(fds.output(2), "{}", e);
+                return Ok(Stage::Finished(1));
+            }
+        };
+        let Some((name, args)) = words.split_first() else {
+            return Ok(Stage::Finished(0));
+        };
+
+        if let Err(e) = self.variables.check_assignable(assignments.iter().map(|(name, _)| name.as_str())) {
+            let _ = writeln!(fds.output(2), "{}", e);
+            return Ok(Stage::Finished(1));
         }
 
-        Ok(())
+        if let Some(function) = self.functions.get(name).cloned() {
+            let mut sub = self.thread_copy();
+            sub.fds = fds;
+            let _ = sub.assign_temporary(&assignments);
+            let args = args.to_vec();
+            return Ok(Stage::Thread(spawn_thread(move || {
+                sub.wait_for_job_start();
+                sub.call_function(&function, &args).unwrap_or_else(|e| {
+                    let _ = writeln!(sub.stderr(), "{}", e);
+                    1
+                })
+            })?));
+        }
+
+        if let Some((name, builtin, args)) = self.find_builtin(name, args) {
+            let mut sub = self.thread_copy();
+            sub.fds = fds;
+            let _ = sub.assign_temporary(&assignments);
+            return Ok(Stage::Thread(spawn_thread(move || {
+                sub.wait_for_job_start();
+                sub.run_builtin(&name, builtin, &args)
+            })?));
+        }
+
+        let (command_name, args) = self.map_command(name, args);
+        Ok(match self.spawn_external(&command_name, &args, &assignments, &fds) {
+            Ok(child) => Stage::Process(child),
+            Err(e) => Stage::Finished(command_failure(&command_name, &e, fds.output(2))),
+        })
     }
 
-    pub fn execute_external_command(&self, command: &str, args: &[String], output_file: Option<String>) -> io::Result<()> {
+    /// Starts an external command in the shell's directory and environment
+    pub fn spawn_external(&self, command: &str, args: &[String], assignments: &[(String, String)], fds: &FdTable) -> io::Result<Child> {
         let mut cmd = Command::new(command);
         cmd.args(args)
             .current_dir(&self.current_dir)
-            .envs(&self.env_vars);
-
-        if let Some(file_path) = output_file {
-            let output = cmd.output()?;
-            let mut file = File::create(file_path)?;
-            file.write_all(&output.stdout)?;
-        } else {
-            cmd.status()?;
+            .env_clear()
+            .envs(self.variables.exported())
+            .envs(assignments.iter().cloned());
+        fds.apply_to(&mut cmd)?;
+        self.traps.apply_ignored(&mut cmd);
+        match &self.process_group {
+            Some(group) => group.spawn(&mut cmd),
+            None => cmd.spawn(),
         }
+    }
 
-        Ok(())
+    pub fn execute_external_command(&self, command: &str, args: &[String], assignments: &[(String, String)], fds: &FdTable) -> io::Result<i32> {
+        let mut child = self.spawn_external(command, args, assignments, fds)?;
+        Ok(exit_code(child.wait()?))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,453 @@
+use std::fs::{self, Metadata};
+use std::io::{self, IsTerminal, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+#[cfg(unix)]
+use std::path::Path;
+
+use filetime::FileTime;
+use regex::Regex;
+
+use crate::ast::CondExpr;
+use crate::error::{BuiltinError, BuiltinResult, TestError};
+use crate::expansion::compile_pattern;
+use crate::shell::Shell;
+use crate::utils::quote_if_needed;
+use crate::variables::Value;
+
+/// Operators taking one operand, shared by `test`, `[` and `[[`
+const UNARY_OPERATORS: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-o", "-p", "-r", "-s", "-t", "-u",
+    "-v", "-w", "-x", "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+/// Operators taking two operands; `[[` also has `=~`
+const BINARY_OPERATORS: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+pub fn is_unary_operator(op: &str) -> bool {
+    UNARY_OPERATORS.contains(&op)
+}
+
+pub fn is_binary_operator(op: &str) -> bool {
+    BINARY_OPERATORS.contains(&op)
+}
+
+fn parse_integer(text: &str) -> Result<i64, TestError> {
+    text.trim().parse().map_err(|_| TestError::IntegerExpected(text.to_string()))
+}
+
+fn compare_integers(left: i64, op: &str, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn modified(metadata: &Metadata) -> FileTime {
+    FileTime::from_last_modification_time(metadata)
+}
+
+/// File tests that need nothing beyond the metadata every platform has
+fn portable_file_test(op: &str, metadata: &Metadata) -> bool {
+    match op {
+        "-a" | "-e" => true,
+        "-f" => metadata.is_file(),
+        "-d" => metadata.is_dir(),
+        "-s" => metadata.len() > 0,
+        _ => false,
+    }
+}
+
+/// Whether the current user may read, write or execute a file, as `mode` asks
+#[cfg(unix)]
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+/// Recursive-descent evaluator over the arguments of `test`: `!`, `-a` and `-o`
+/// combine primaries, and `(` `)` group them
+struct TestArgs<'a> {
+    shell: &'a Shell,
+    args: &'a [String],
+    pos: usize,
+}
+
+impl TestArgs<'_> {
+    fn accept(&mut self, arg: &str) -> bool {
+        if self.args.get(self.pos).map(String::as_str) == Some(arg) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<bool, TestError> {
+        let mut value = self.and()?;
+        while self.accept("-o") {
+            let right = self.and()?;
+            value = value || right;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, TestError> {
+        let mut value = self.not()?;
+        while self.accept("-a") {
+            let right = self.not()?;
+            value = value && right;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<bool, TestError> {
+        if self.pos + 1 < self.args.len() && self.accept("!") {
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, TestError> {
+        let args = self.args;
+        let Some(arg) = args.get(self.pos) else {
+            return Err(TestError::ArgumentExpected);
+        };
+        // `[ -n = -n ]` compares two strings, so binary operators are tried first
+        if let (Some(op), Some(right)) = (args.get(self.pos + 1), args.get(self.pos + 2)) {
+            if is_binary_operator(op) {
+                self.pos += 3;
+                return self.shell.binary_test(arg, op, right);
+            }
+        }
+        if arg == "(" && self.pos + 1 < args.len() {
+            self.pos += 1;
+            let value = self.or()?;
+            if !self.accept(")") {
+                return Err(TestError::ParenExpected);
+            }
+            return Ok(value);
+        }
+        if let Some(operand) = args.get(self.pos + 1).filter(|_| is_unary_operator(arg)) {
+            self.pos += 2;
+            return Ok(self.shell.unary_test(arg, operand));
+        }
+        self.pos += 1;
+        Ok(!arg.is_empty())
+    }
+}
+
+impl Shell {
+    /// `test expression`: the status is 0 if the expression is true, 1 if it is false
+    /// and 2 if it is malformed
+    pub fn test(&mut self, args: &[String]) -> BuiltinResult {
+        if args.is_empty() {
+            return Ok(1);
+        }
+        let mut parser = TestArgs { shell: self, args, pos: 0 };
+        let value = parser.or()?;
+        if parser.pos < args.len() {
+            return Err(match args.len() {
+                2 => TestError::UnaryExpected(args[0].clone()),
+                3 => TestError::BinaryExpected(args[1].clone()),
+                _ => TestError::TooManyArguments,
+            }
+            .into());
+        }
+        Ok(i32::from(!value))
+    }
+
+    /// `[ expression ]`, the same as `test` but with a closing `]`
+    pub fn bracket(&mut self, args: &[String]) -> BuiltinResult {
+        match args.split_last() {
+            Some((last, args)) if last == "]" => self.test(args),
+            _ => Err(BuiltinError::from(TestError::MissingBracket)),
+        }
+    }
+
+    /// Evaluates a unary test such as `-f path` or `-z string`
+    fn unary_test(&self, op: &str, operand: &str) -> bool {
+        match op {
+            "-z" => operand.is_empty(),
+            "-n" => !operand.is_empty(),
+            "-o" => self.options.get(operand).unwrap_or(false),
+            "-v" => self.lookup_variable(operand).is_some(),
+            "-t" => operand
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|fd| self.fds.file(fd).ok().flatten())
+                .is_some_and(|file| file.is_terminal()),
+            _ => self.file_test(op, operand),
+        }
+    }
+
+    /// Evaluates a file test; relative paths are taken from the shell's directory
+    fn file_test(&self, op: &str, path: &str) -> bool {
+        if path.is_empty() {
+            return false;
+        }
+        let path = self.current_dir.join(path);
+        if op == "-h" || op == "-L" {
+            return fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        }
+        let Ok(metadata) = fs::metadata(&path) else {
+            return false;
+        };
+
+        #[cfg(unix)]
+        {
+            let mode = metadata.mode();
+            let file_type = metadata.file_type();
+            match op {
+                "-r" => accessible(&path, libc::R_OK),
+                "-w" => accessible(&path, libc::W_OK),
+                "-x" => accessible(&path, libc::X_OK),
+                "-b" => file_type.is_block_device(),
+                "-c" => file_type.is_char_device(),
+                "-p" => file_type.is_fifo(),
+                "-S" => file_type.is_socket(),
+                "-u" => mode & 0o4000 != 0,
+                "-g" => mode & 0o2000 != 0,
+                "-k" => mode & 0o1000 != 0,
+                "-O" => metadata.uid() == unsafe { libc::geteuid() },
+                "-G" => metadata.gid() == unsafe { libc::getegid() },
+                "-N" => metadata.mtime() > metadata.atime(),
+                _ => portable_file_test(op, &metadata),
+            }
+        }
+        #[cfg(not(unix))]
+        match op {
+            "-r" | "-x" => true,
+            "-w" => !metadata.permissions().readonly(),
+            _ => portable_file_test(op, &metadata),
+        }
+    }
+
+    /// Evaluates a binary test on strings, integers or files
+    fn binary_test(&self, left: &str, op: &str, right: &str) -> Result<bool, TestError> {
+        Ok(match op {
+            "=" | "==" => left == right,
+            "!=" => left != right,
+            "<" => left < right,
+            ">" => left > right,
+            "-nt" | "-ot" | "-ef" => self.compare_files(left, op, right),
+            _ => compare_integers(parse_integer(left)?, op, parse_integer(right)?),
+        })
+    }
+
+    /// `-nt` and `-ot` compare modification times; a file that exists is newer than
+    /// one that does not. `-ef` is true for two names of the same file
+    fn compare_files(&self, left: &str, op: &str, right: &str) -> bool {
+        let left = fs::metadata(self.current_dir.join(left)).ok();
+        let right = fs::metadata(self.current_dir.join(right)).ok();
+        match (op, left, right) {
+            ("-nt", Some(left), Some(right)) => modified(&left) > modified(&right),
+            ("-nt", left, _) => left.is_some(),
+            ("-ot", Some(left), Some(right)) => modified(&left) < modified(&right),
+            ("-ot", _, right) => right.is_some(),
+            #[cfg(unix)]
+            (_, Some(left), Some(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+            _ => false,
+        }
+    }
+
+    /// Runs `[[ expression ]]`; a malformed regular expression or integer gives 2
+    pub fn execute_conditional(&mut self, expr: &CondExpr) -> io::Result<i32> {
+        match self.evaluate_conditional(expr)? {
+            Ok(value) => Ok(i32::from(!value)),
+            Err(e) => {
+                let _ = writeln!(self.stderr(), "[[: {}", e);
+                Ok(2)
+            }
+        }
+    }
+
+    /// Evaluates a `[[` expression. The outer error aborts the command, as a failed
+    /// expansion does; the inner one makes its status 2
+    fn evaluate_conditional(&mut self, expr: &CondExpr) -> io::Result<Result<bool, TestError>> {
+        Ok(match expr {
+            CondExpr::And(left, right) => match self.evaluate_conditional(left)? {
+                Ok(true) => self.evaluate_conditional(right)?,
+                other => other,
+            },
+            CondExpr::Or(left, right) => match self.evaluate_conditional(left)? {
+                Ok(false) => self.evaluate_conditional(right)?,
+                other => other,
+            },
+            CondExpr::Not(inner) => self.evaluate_conditional(inner)?.map(|value| !value),
+            CondExpr::Word(word) => {
+                let text = self.expand_word(word)?;
+                self.trace_test(&[quote_if_needed(&text)]);
+                Ok(!text.is_empty())
+            }
+            CondExpr::Unary(op, word) => {
+                let operand = self.expand_word(word)?;
+                self.trace_test(&[op.clone(), quote_if_needed(&operand)]);
+                Ok(self.unary_test(op, &operand))
+            }
+            CondExpr::Binary(left, op, right) => {
+                let left = self.expand_word(left)?;
+                match op.as_str() {
+                    "=" | "==" | "!=" => {
+                        let pattern = self.expand_pattern(right)?;
+                        self.trace_test(&[quote_if_needed(&left), op.clone(), pattern.clone()]);
+                        Ok(compile_pattern(&pattern).matches(&left) == (op != "!="))
+                    }
+                    "=~" => {
+                        let pattern = self.expand_regex(right)?;
+                        self.trace_test(&[quote_if_needed(&left), op.clone(), pattern.clone()]);
+                        Regex::new(&pattern)
+                            .map(|regex| self.regex_test(&regex, &left))
+                            .map_err(|_| TestError::Regex(pattern))
+                    }
+                    // Integer operands are arithmetic expressions inside `[[ ]]`
+                    "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                        let right = self.expand_word(right)?;
+                        self.trace_test(&[quote_if_needed(&left), op.clone(), quote_if_needed(&right)]);
+                        self.evaluate_arithmetic(&left)
+                            .and_then(|left| Ok(compare_integers(left, op, self.evaluate_arithmetic(&right)?)))
+                            .map_err(TestError::from)
+                    }
+                    _ => {
+                        let right = self.expand_word(right)?;
+                        self.trace_test(&[quote_if_needed(&left), op.clone(), quote_if_needed(&right)]);
+                        self.binary_test(&left, op, &right)
+                    }
+                }
+            }
+        })
+    }
+
+    /// Traces one test of a `[[` expression as it is evaluated, the way bash does
+    fn trace_test(&self, words: &[String]) {
+        let words = words.iter().cloned();
+        self.trace_command(std::iter::once("[[".to_string()).chain(words).chain(std::iter::once("]]".to_string())));
+    }
+
+    /// Matches `text` against `regex`, recording the match and its groups in
+    /// `BASH_REMATCH`
+    fn regex_test(&mut self, regex: &Regex, text: &str) -> bool {
+        let captures = regex.captures(text);
+        let groups = captures.iter().flat_map(|captures| captures.iter());
+        let groups = groups.map(|group| group.map_or_else(String::new, |m| m.as_str().to_string()));
+        self.variables.declare("BASH_REMATCH").value = Some(Value::list(groups));
+        captures.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variables::Key;
+
+    /// Runs `test` and returns its status, or the status its error gives
+    fn test(args: &[&str]) -> i32 {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Shell::for_tests().test(&args).unwrap_or_else(|e| e.exit_code())
+    }
+
+    fn run(shell: &mut Shell, line: &str) -> i32 {
+        shell.execute_command(line).unwrap();
+        shell.last_status
+    }
+
+    #[test]
+    fn argument_counts() {
+        assert_eq!(test(&[]), 1);
+        assert_eq!(test(&[""]), 1);
+        assert_eq!(test(&["-n"]), 0);
+        assert_eq!(test(&["!", "x"]), 1);
+        assert_eq!(test(&["-z", ""]), 0);
+        assert_eq!(test(&["-n", ""]), 1);
+        assert_eq!(test(&["x", "y"]), 2);
+        assert_eq!(test(&["a", "=", "a"]), 0);
+        assert_eq!(test(&["a", "!=", "a"]), 1);
+        assert_eq!(test(&["-n", "=", "-n"]), 0);
+        assert_eq!(test(&["!", "-z", "x"]), 0);
+        assert_eq!(test(&["a", "b", "c"]), 2);
+        assert_eq!(test(&["1", "-eq", "a"]), 2);
+        assert_eq!(test(&["!", "a", "=", "b"]), 0);
+        assert_eq!(test(&["a", "b", "c", "d", "e"]), 2);
+    }
+
+    #[test]
+    fn and_or_and_parentheses() {
+        assert_eq!(test(&["x", "-a", ""]), 1);
+        assert_eq!(test(&["x", "-o", ""]), 0);
+        // -a binds tighter than -o
+        assert_eq!(test(&["", "-o", "x", "-a", ""]), 1);
+        assert_eq!(test(&["(", "", "-o", "x", ")", "-a", "x"]), 0);
+        assert_eq!(test(&["(", "x", ")"]), 0);
+        assert_eq!(test(&["(", "x", "y"]), 2);
+    }
+
+    #[test]
+    fn bracket_needs_closing_bracket() {
+        let mut shell = Shell::for_tests();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(shell.bracket(&args(&["x", "]"])).unwrap(), 0);
+        assert_eq!(shell.bracket(&args(&["]"])).unwrap(), 1);
+        assert_eq!(shell.bracket(&args(&["x"])).unwrap_err().exit_code(), 2);
+    }
+
+    #[test]
+    fn newer_and_older_files() {
+        let dir = std::env::temp_dir().join(format!("shell-conditional-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("old");
+        let new = dir.join("new");
+        fs::write(&old, "").unwrap();
+        fs::write(&new, "").unwrap();
+        filetime::set_file_mtime(&old, FileTime::from_unix_time(1_000, 0)).unwrap();
+        filetime::set_file_mtime(&new, FileTime::from_unix_time(2_000, 0)).unwrap();
+        let (old, new) = (old.to_str().unwrap(), new.to_str().unwrap());
+        let missing = dir.join("missing");
+        let missing = missing.to_str().unwrap();
+
+        assert_eq!(test(&[new, "-nt", old]), 0);
+        assert_eq!(test(&[old, "-nt", new]), 1);
+        assert_eq!(test(&[old, "-ot", new]), 0);
+        assert_eq!(test(&[new, "-ot", old]), 1);
+        assert_eq!(test(&[new, "-nt", missing]), 0);
+        assert_eq!(test(&[missing, "-nt", new]), 1);
+        assert_eq!(test(&[missing, "-ot", new]), 0);
+        assert_eq!(test(&[new, "-ot", missing]), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pattern_matching_respects_quotes() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(run(&mut shell, "[[ abc == a* ]]"), 0);
+        assert_eq!(run(&mut shell, "[[ abc == \"a*\" ]]"), 1);
+        assert_eq!(run(&mut shell, "[[ 'a*' == \"a*\" ]]"), 0);
+        assert_eq!(run(&mut shell, "[[ abc != a?c ]]"), 1);
+        run(&mut shell, "p='a*'");
+        assert_eq!(run(&mut shell, "[[ abc == $p ]]"), 0);
+        assert_eq!(run(&mut shell, "[[ abc == \"$p\" ]]"), 1);
+    }
+
+    #[test]
+    fn regex_matches_set_bash_rematch() {
+        let mut shell = Shell::for_tests();
+        assert_eq!(run(&mut shell, "[[ foo123bar =~ ([a-z]+)([0-9]+) ]]"), 0);
+        let group = |shell: &Shell, index| shell.variables.element("BASH_REMATCH", &Key::Index(index)).map(str::to_string);
+        assert_eq!(group(&shell, 0).as_deref(), Some("foo123"));
+        assert_eq!(group(&shell, 1).as_deref(), Some("foo"));
+        assert_eq!(group(&shell, 2).as_deref(), Some("123"));
+
+        // Quoted parts of the regular expression match literally
+        assert_eq!(run(&mut shell, "[[ abc =~ \"a.c\" ]]"), 1);
+        assert_eq!(group(&shell, 0), None);
+        assert_eq!(run(&mut shell, "[[ a.c =~ ^\"a.c\"$ ]]"), 0);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use crate::ast::{CaseItem, CaseTerminator, FunctionDef, List, Word};
+use crate::shell::Shell;
+use crate::traps::Trap;
+use crate::utils::quote_if_needed;
+
+/// A `break` or `continue` that is unwinding the commands between it and its loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Break(usize),
+    Continue(usize),
+    /// `return` with the function's exit status
+    Return(i32),
+    /// Leaving the shell with the given status, as `set -e` does after a failure
+    Exit(i32),
+}
+
+/// How many function calls may be running at once before a call is refused
+const MAX_FUNCTION_DEPTH: usize = 1000;
+
+impl Shell {
+    /// Settles a pending `break` or `continue` at the end of a loop body; returns true
+    /// if the loop has to stop, as it also does after Ctrl-C
+    fn loop_should_stop(&mut self) -> bool {
+        if self.interrupted() {
+            return true;
+        }
+        match self.flow {
+            Some(Flow::Break(n)) => {
+                self.flow = (n > 1).then(|| Flow::Break(n - 1));
+                true
+            }
+            Some(Flow::Continue(n)) if n > 1 => {
+                self.flow = Some(Flow::Continue(n - 1));
+                true
+            }
+            Some(Flow::Continue(_)) => {
+                self.flow = None;
+                false
+            }
+            Some(Flow::Return(_) | Flow::Exit(_)) => true,
+            None => false,
+        }
+    }
+
+    /// Runs a loop body, keeping track of how many loops are active for `break`
+    fn run_loop_body(&mut self, body: &List) -> io::Result<i32> {
+        self.loop_depth += 1;
+        let result = self.execute_list(body);
+        self.loop_depth -= 1;
+        result
+    }
+
+    /// Runs the condition of an `if`, `while` or `until`, where a failing command is
+    /// being tested rather than going wrong
+    fn execute_condition(&mut self, condition: &List) -> io::Result<i32> {
+        self.condition_depth += 1;
+        let result = self.execute_list(condition);
+        self.condition_depth -= 1;
+        result
+    }
+
+    pub fn execute_if(&mut self, branches: &[(List, List)], else_branch: Option<&List>) -> io::Result<i32> {
+        for (condition, body) in branches {
+            let status = self.execute_condition(condition)?;
+            if self.flow.is_some() {
+                return Ok(status);
+            }
+            if status == 0 {
+                return self.execute_list(body);
+            }
+        }
+        match else_branch {
+            Some(body) => self.execute_list(body),
+            None => Ok(0),
+        }
+    }
+
+    pub fn execute_while(&mut self, condition: &List, body: &List, until: bool) -> io::Result<i32> {
+        let mut status = 0;
+        loop {
+            self.loop_depth += 1;
+            let result = self.execute_condition(condition);
+            self.loop_depth -= 1;
+            if self.loop_should_stop() {
+                break;
+            }
+            if (result? == 0) == until {
+                break;
+            }
+            status = self.run_loop_body(body)?;
+            if self.loop_should_stop() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    pub fn execute_for(&mut self, name: &str, words: Option<&[Word]>, body: &List) -> io::Result<i32> {
+        let values = match words {
+            Some(words) => self.expand_words(words)?,
+            None => self.positional_params.clone(),
+        };
+        let mut status = 0;
+        for value in values.iter().cloned() {
+            let head = ["for", name, "in"].into_iter().map(str::to_string);
+            self.trace_command(head.chain(values.iter().map(|value| quote_if_needed(value))));
+            if let Err(e) = self.variables.set(name, value) {
+                let _ = writeln!(self.stderr(), "{}", e);
+                return Ok(1);
+            }
+            status = self.run_loop_body(body)?;
+            if self.loop_should_stop() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    pub fn execute_arithmetic_for(&mut self, init: &Word, condition: &Word, step: &Word, body: &List) -> io::Result<i32> {
+        let mut status = 0;
+        self.run_arithmetic(init)?;
+        loop {
+            // An empty condition is always true
+            if !condition.parts.is_empty() && self.run_arithmetic(condition)? != 0 {
+                break;
+            }
+            status = self.run_loop_body(body)?;
+            if self.loop_should_stop() {
+                break;
+            }
+            self.run_arithmetic(step)?;
+        }
+        Ok(status)
+    }
+
+    pub fn execute_case(&mut self, word: &Word, items: &[CaseItem]) -> io::Result<i32> {
+        let text = self.expand_word(word)?;
+        let mut status = 0;
+        let mut fall_through = false;
+        for item in items {
+            if !fall_through && !self.case_item_matches(item, &text)? {
+                continue;
+            }
+            status = self.execute_list(&item.body)?;
+            match item.terminator {
+                CaseTerminator::Break => break,
+                CaseTerminator::FallThrough => fall_through = true,
+                CaseTerminator::Continue => fall_through = false,
+            }
+            if self.flow.is_some() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    fn case_item_matches(&mut self, item: &CaseItem, text: &str) -> io::Result<bool> {
+        for pattern in &item.patterns {
+            if self.pattern_matches(pattern, text)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs a function with `args` as its positional parameters. Variables made
+    /// `local` inside it get their previous values back when it returns
+    pub fn call_function(&mut self, function: &FunctionDef, args: &[String]) -> io::Result<i32> {
+        if self.local_scopes.len() >= MAX_FUNCTION_DEPTH {
+            return Err(io::Error::other(format!(
+                "{}: maximum function nesting level exceeded ({})",
+                function.name, MAX_FUNCTION_DEPTH
+            )));
+        }
+        let saved_params = std::mem::replace(&mut self.positional_params, args.to_vec());
+        self.local_scopes.push(HashMap::new());
+        let hidden_traps = self.traps.take_function_traps();
+
+        let result = self.execute_redirected(&function.body, &function.redirects);
+        self.run_trap(Trap::Return);
+        self.traps.restore_function_traps(hidden_traps);
+
+        for (name, variable) in self.local_scopes.pop().unwrap_or_default() {
+            self.variables.restore(&name, variable);
+        }
+        self.positional_params = saved_params;
+        if let Some(Flow::Return(status)) = self.flow {
+            self.flow = None;
+            return Ok(status);
+        }
+        result
+    }
+}
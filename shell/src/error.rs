@@ -0,0 +1,147 @@
+use std::io;
+use thiserror::Error;
+
+/// Ways a builtin command can fail. The error is reported as `name: message` and turned
+/// into the builtin's exit status
+#[derive(Debug, Error)]
+pub enum BuiltinError {
+    #[error("missing {0}")]
+    MissingOperand(&'static str),
+    #[error("{0}")]
+    Usage(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("{0}: not found")]
+    NotFound(String),
+    #[error("{path}: {}", describe_io_error(source))]
+    Path {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{}", describe_io_error(.0))]
+    Io(#[from] io::Error),
+    /// Stopped by Ctrl-C; nothing is reported
+    #[error("interrupted")]
+    Interrupted,
+}
+
+impl BuiltinError {
+    pub fn path(path: impl Into<String>, source: io::Error) -> BuiltinError {
+        BuiltinError::Path { path: path.into(), source }
+    }
+
+    /// Exit status the failing builtin reports; usage errors use 2 like other shells
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BuiltinError::Usage(_) => 2,
+            BuiltinError::Interrupted => crate::signals::INTERRUPTED_STATUS,
+            _ => 1,
+        }
+    }
+}
+
+/// Failure to set up a command's redirections; the command is not run
+#[derive(Debug, Error)]
+pub enum RedirectionError {
+    #[error("{path}: {}", describe_io_error(source))]
+    Open {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{0}: bad file descriptor")]
+    BadDescriptor(String),
+    #[error("{0}: ambiguous redirect")]
+    Ambiguous(String),
+    #[error("{0}: cannot overwrite existing file")]
+    Clobber(String),
+    #[error("cannot create temp file for here-document: {}", describe_io_error(.0))]
+    HereDoc(#[source] io::Error),
+}
+
+/// Failure to change a variable
+#[derive(Debug, Error)]
+pub enum VariableError {
+    #[error("{0}: readonly variable")]
+    Readonly(String),
+    #[error("{0}: bad array subscript")]
+    BadSubscript(String),
+    /// An arithmetic subscript that failed to evaluate
+    #[error("{0}")]
+    Subscript(String),
+    #[error("{0}: cannot assign list to array member")]
+    ListToElement(String),
+    #[error("{0}: must use subscript when assigning associative array")]
+    NeedsKey(String),
+    #[error("{0}: cannot convert indexed to associative array")]
+    ToAssociative(String),
+    #[error("{0}: cannot convert associative to indexed array")]
+    ToIndexed(String),
+}
+
+impl From<VariableError> for BuiltinError {
+    fn from(error: VariableError) -> BuiltinError {
+        BuiltinError::InvalidArgument(error.to_string())
+    }
+}
+
+/// Failure to evaluate an arithmetic expression
+#[derive(Debug, Error)]
+pub enum ArithmeticError {
+    #[error("division by 0")]
+    DivisionByZero,
+    #[error("exponent less than 0")]
+    NegativeExponent,
+    #[error("syntax error: operand expected")]
+    MissingOperand,
+    #[error("syntax error in expression (error token is \"{0}\")")]
+    Syntax(String),
+    #[error("value too great for base (error token is \"{0}\")")]
+    InvalidNumber(String),
+    #[error("expression recursion level exceeded")]
+    RecursionLimit,
+    #[error("{0}")]
+    Variable(#[from] VariableError),
+}
+
+/// Failure to evaluate a `test`, `[` or `[[` expression; the command's status is 2
+#[derive(Debug, Error)]
+pub enum TestError {
+    #[error("{0}: unary operator expected")]
+    UnaryExpected(String),
+    #[error("{0}: binary operator expected")]
+    BinaryExpected(String),
+    #[error("{0}: integer expression expected")]
+    IntegerExpected(String),
+    #[error("argument expected")]
+    ArgumentExpected,
+    #[error("`)' expected")]
+    ParenExpected,
+    #[error("missing `]'")]
+    MissingBracket,
+    #[error("too many arguments")]
+    TooManyArguments,
+    #[error("{0}: invalid regular expression")]
+    Regex(String),
+    #[error("{0}")]
+    Arithmetic(#[from] ArithmeticError),
+}
+
+impl From<TestError> for BuiltinError {
+    fn from(error: TestError) -> BuiltinError {
+        BuiltinError::Usage(error.to_string())
+    }
+}
+
+/// Formats an I/O error without the trailing "(os error N)"
+pub fn describe_io_error(error: &io::Error) -> String {
+    let message = error.to_string();
+    match message.find(" (os error") {
+        Some(index) => message[..index].to_string(),
+        None => message,
+    }
+}
+
+/// Result returned by every builtin: its exit status or the error that stopped it
+pub type BuiltinResult = Result<i32, BuiltinError>;
@@ -0,0 +1,949 @@
+use std::borrow::Cow;
+use std::io;
+use std::fs;
+use std::path::Path;
+use std::slice;
+use glob::Pattern;
+use crate::ast::{ParamExpansion, ParamOp, ReplaceMode, Word, WordPart};
+use crate::brace::expand_braces;
+use crate::parser;
+use crate::shell::Shell;
+use crate::utils::quote;
+use crate::variables::Value;
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// How a piece of an expanded word was quoted, which decides whether it is subject to
+/// field splitting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SegmentKind {
+    /// Unquoted text written in the source
+    Literal,
+    /// Unquoted result of an expansion
+    Expanded,
+    Quoted,
+    /// Boundary between the positional parameters produced by `$@` or `$*`
+    FieldBreak,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    text: String,
+    kind: SegmentKind,
+}
+
+/// Quoting context a word part is expanded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Unquoted,
+    /// Operand of a `${...}` operator outside double quotes; its text is split like
+    /// the result of an expansion
+    Nested,
+    DoubleQuoted,
+}
+
+impl Shell {
+    /// Expands words into command arguments: brace and tilde expansion, parameter
+    /// expansion, field splitting, pathname expansion and quote removal
+    pub fn expand_words(&mut self, words: &[Word]) -> io::Result<Vec<String>> {
+        let mut fields = Vec::new();
+        for word in words.iter().flat_map(expand_braces) {
+            let parts = self.expand_tildes(&word.parts, false);
+            let segments = self.expand_parts(&parts, Context::Unquoted)?;
+            for field in split_fields(&segments, &self.ifs()) {
+                if field.has_glob && !self.options.noglob {
+                    fields.extend(self.expand_glob(field)?);
+                } else {
+                    fields.push(field.text);
+                }
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Replaces a field containing a pattern by the sorted paths it matches. Without
+    /// matches the field is kept as it is, unless `nullglob` or `failglob` is set
+    fn expand_glob(&self, field: Field) -> io::Result<Vec<String>> {
+        let matches = self.glob_matches(&field.pattern);
+        if !matches.is_empty() {
+            return Ok(matches);
+        }
+        if self.options.failglob {
+            return Err(io::Error::other(format!("no match: {}", field.text)));
+        }
+        if self.options.nullglob {
+            return Ok(Vec::new());
+        }
+        Ok(vec![field.text])
+    }
+
+    /// Lists the paths matching `pattern`, one `/`-separated component at a time.
+    /// Relative patterns are resolved against the shell's current directory and
+    /// produce relative paths
+    fn glob_matches(&self, pattern: &str) -> Vec<String> {
+        let (mut prefixes, pattern) = match pattern.strip_prefix('/') {
+            Some(rest) => (vec!["/".to_string()], rest),
+            None => (vec![String::new()], pattern),
+        };
+        let components: Vec<&str> = pattern.split('/').collect();
+
+        for (i, &component) in components.iter().enumerate() {
+            let last = i == components.len() - 1;
+            let mut next = Vec::new();
+            for prefix in &prefixes {
+                let dir = self.current_dir.join(prefix);
+                if let Some(literal) = literal_component(component) {
+                    // An empty component comes from a trailing or doubled `/`
+                    if literal.is_empty() || last || dir.join(&literal).is_dir() {
+                        next.push(format!("{}{}", prefix, literal));
+                    }
+                } else if component == "**" && self.options.globstar {
+                    self.glob_recursive(&dir, prefix, last, &mut next);
+                } else {
+                    let component = if component.contains("**") { component.replace("**", "*") } else { component.to_string() };
+                    let Ok(pattern) = Pattern::new(&component) else {
+                        continue;
+                    };
+                    for name in self.glob_entries(&dir, component.starts_with('.')) {
+                        if pattern.matches(&name) && (last || dir.join(&name).is_dir()) {
+                            next.push(format!("{}{}", prefix, name));
+                        }
+                    }
+                }
+            }
+            if !last {
+                // An empty prefix stands for the current directory itself
+                next.iter_mut().filter(|path| !path.is_empty()).for_each(|path| path.push('/'));
+            }
+            prefixes = next;
+        }
+
+        let mut matches: Vec<String> = prefixes
+            .into_iter()
+            .filter(|path| !path.is_empty() && fs::symlink_metadata(self.current_dir.join(path)).is_ok())
+            .collect();
+        matches.sort();
+        matches
+    }
+
+    /// Sorted names in `dir` a pattern component may match; names starting with `.`
+    /// are only included with `dotglob` or when the pattern itself starts with `.`
+    fn glob_entries(&self, dir: &Path, dotfiles: bool) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| dotfiles || self.options.dotglob || !name.starts_with('.'))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Expands a `**` component: `prefix` itself and every directory below it, or with
+    /// `last` every file below it
+    fn glob_recursive(&self, dir: &Path, prefix: &str, last: bool, out: &mut Vec<String>) {
+        if !last {
+            // `prefix` ends with `/` (or is empty); the caller adds the separator again
+            out.push(prefix.strip_suffix('/').unwrap_or(prefix).to_string());
+        }
+        for name in self.glob_entries(dir, false) {
+            let path = dir.join(&name);
+            let is_dir = fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_dir());
+            // Without `last` directories are added by the recursive call
+            if last {
+                out.push(format!("{}{}", prefix, name));
+            }
+            if is_dir {
+                self.glob_recursive(&path, &format!("{}{}/", prefix, name), last, out);
+            }
+        }
+    }
+
+    /// Expands a word into a single string without field splitting, as done for
+    /// redirection targets
+    pub fn expand_word(&mut self, word: &Word) -> io::Result<String> {
+        let parts = self.expand_tildes(&word.parts, false);
+        self.join_expansion(&parts)
+    }
+
+    /// Expands the value of a `NAME=value` assignment, in which a `~` after any `:`
+    /// is expanded too, as in `PATH=~/bin:~/.local/bin`
+    pub fn expand_assignment_value(&mut self, word: &Word) -> io::Result<String> {
+        let parts = self.expand_tildes(&word.parts, true);
+        self.join_expansion(&parts)
+    }
+
+    fn join_expansion(&mut self, parts: &[WordPart]) -> io::Result<String> {
+        let segments = self.expand_parts(parts, Context::Unquoted)?;
+        Ok(segments
+            .into_iter()
+            .map(|segment| match segment.kind {
+                SegmentKind::FieldBreak => " ".to_string(),
+                _ => segment.text,
+            })
+            .collect())
+    }
+
+    /// Replaces an unquoted `~`, `~user`, `~+` or `~-` at the start of a word (and
+    /// after each `:` in assignments) up to the next `/` with the directory it names.
+    /// The directory is quoted so that it is not split or globbed
+    fn expand_tildes<'a>(&self, parts: &'a [WordPart], assignment: bool) -> Cow<'a, [WordPart]> {
+        let has_tilde = |i: usize| matches!(&parts[i], WordPart::Literal(text) if text.contains('~'));
+        if !(0..parts.len()).any(|i| (i == 0 || assignment) && has_tilde(i)) {
+            return Cow::Borrowed(parts);
+        }
+
+        let mut expanded = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let WordPart::Literal(text) = part else {
+                expanded.push(part.clone());
+                continue;
+            };
+            let is_last = i == parts.len() - 1;
+            let mut literal = String::new();
+            let mut rest = text.as_str();
+            let mut at_start = i == 0;
+            while !rest.is_empty() {
+                if at_start && rest.starts_with('~') {
+                    let end = rest.find(|c| c == '/' || (assignment && c == ':'));
+                    // A prefix running into a quoted or expanded part is left alone
+                    let home = match end {
+                        Some(end) => self.tilde_home(&rest[1..end]),
+                        None if is_last => self.tilde_home(&rest[1..]),
+                        None => None,
+                    };
+                    if let Some(home) = home {
+                        if !literal.is_empty() {
+                            expanded.push(WordPart::Literal(std::mem::take(&mut literal)));
+                        }
+                        expanded.push(WordPart::Quoted(home));
+                        rest = &rest[end.unwrap_or(rest.len())..];
+                        at_start = false;
+                        continue;
+                    }
+                }
+                let c = rest.chars().next().unwrap();
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+                at_start = assignment && c == ':';
+            }
+            if !literal.is_empty() {
+                expanded.push(WordPart::Literal(literal));
+            }
+        }
+        Cow::Owned(expanded)
+    }
+
+    /// The directory named by a tilde prefix (without the `~`)
+    fn tilde_home(&self, user: &str) -> Option<String> {
+        match user {
+            "" => Some(self.lookup_variable("HOME").unwrap_or_else(|| self.home_dir.to_string_lossy().into_owned())),
+            "+" => Some(self.current_dir.to_string_lossy().into_owned()),
+            "-" => self.lookup_variable("OLDPWD"),
+            _ => user_home(user),
+        }
+    }
+
+    /// Expands a word used as a pattern; quoted characters lose their special meaning
+    pub fn expand_pattern(&mut self, word: &Word) -> io::Result<String> {
+        let segments = self.expand_parts(&word.parts, Context::Unquoted)?;
+        Ok(segments
+            .iter()
+            .map(|segment| match segment.kind {
+                SegmentKind::Quoted => Pattern::escape(&segment.text),
+                _ => segment.text.clone(),
+            })
+            .collect())
+    }
+
+    /// Expands the right side of `=~` into a regular expression; quoted text matches
+    /// literally
+    pub fn expand_regex(&mut self, word: &Word) -> io::Result<String> {
+        let segments = self.expand_parts(&word.parts, Context::Unquoted)?;
+        Ok(segments
+            .iter()
+            .map(|segment| match segment.kind {
+                SegmentKind::Quoted => regex::escape(&segment.text),
+                _ => segment.text.clone(),
+            })
+            .collect())
+    }
+
+    /// Matches `text` against a pattern word, as `case` does
+    pub fn pattern_matches(&mut self, pattern: &Word, text: &str) -> io::Result<bool> {
+        let pattern = self.expand_pattern(pattern)?;
+        Ok(compile_pattern(&pattern).matches(text))
+    }
+
+    fn ifs(&self) -> String {
+        self.variables.get("IFS").map(str::to_string).unwrap_or_else(|| DEFAULT_IFS.to_string())
+    }
+
+    /// Returns the value of a variable or special parameter, or `None` if it is unset
+    pub fn lookup_variable(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some(self.positional_params.len().to_string()),
+            "0" => Some(self.shell_name.clone()),
+            "-" => Some(self.options.letters()),
+            "@" | "*" => Some(self.positional_params.join(" ")),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                let index: usize = name.parse().ok()?;
+                self.positional_params.get(index - 1).cloned()
+            }
+            _ => self.variables.get(name).map(str::to_string),
+        }
+    }
+
+    /// Returns the elements of an array variable; a plain variable is an array with
+    /// one element
+    pub fn lookup_array(&self, name: &str) -> Vec<String> {
+        match self.variables.value(name) {
+            Some(value) => value.values(),
+            None => self.lookup_variable(name).into_iter().collect(),
+        }
+    }
+
+    /// Returns the value of `${name[index]}`; `@` and `*` join every element
+    fn lookup_element(&mut self, name: &str, index: &str) -> io::Result<Option<String>> {
+        if let "@" | "*" = index {
+            let values = self.lookup_array(name);
+            return Ok((!values.is_empty()).then(|| values.join(" ")));
+        }
+        if self.variables.value(name).is_none() {
+            return Ok(None);
+        }
+        let key = self.element_key(name, index).map_err(io::Error::other)?;
+        Ok(self.variables.element(name, &key).map(str::to_string))
+    }
+
+    /// Expands the subscript of `${name[index]}` or `name[index]=value` into its text
+    pub fn expand_subscript(&mut self, index: &Word) -> io::Result<String> {
+        let segments = self.expand_parts(&index.parts, Context::DoubleQuoted)?;
+        Ok(segments.into_iter().map(|segment| segment.text).collect())
+    }
+
+    /// Expands the elements of an array list: `[index]=value` elements keep their
+    /// subscript and are not split, while the others expand like command arguments
+    pub fn expand_array(&mut self, elements: &[Word]) -> io::Result<Vec<(Option<String>, String)>> {
+        let mut expanded = Vec::new();
+        for word in elements {
+            match parser::split_array_element(word) {
+                Some((index, value)) => {
+                    let index = self.expand_subscript(&index)?;
+                    expanded.push((Some(index), self.expand_assignment_value(&value)?));
+                }
+                None => expanded.extend(self.expand_words(slice::from_ref(word))?.into_iter().map(|field| (None, field))),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Picks the elements of `${name[@]:offset:length}` by index; a negative offset
+    /// counts back from the end
+    fn slice_elements(&mut self, elements: Vec<(usize, String)>, offset: &Word, length: Option<&Word>) -> io::Result<Vec<String>> {
+        let end = elements.last().map_or(0, |(index, _)| index + 1) as i64;
+        let mut start = self.arithmetic_expansion(&offset.parts)?;
+        if start < 0 {
+            start += end;
+        }
+        let length = match length {
+            Some(length) => Some(self.arithmetic_expansion(&length.parts)?),
+            None => None,
+        };
+        if start < 0 {
+            return Ok(Vec::new());
+        }
+        let selected = elements.into_iter().filter(|&(index, _)| index as i64 >= start).map(|(_, value)| value);
+        match length {
+            Some(length) if length < 0 => Err(io::Error::other(format!("{}: substring expression < 0", length))),
+            Some(length) => Ok(selected.take(length as usize).collect()),
+            None => Ok(selected.collect()),
+        }
+    }
+
+    /// Expands a list of values the way `$@` and `$*` are expanded: one field per
+    /// value, except for `"$*"`, which joins them with the first character of `IFS`
+    fn expand_fields(&self, values: &[String], star: bool, context: Context, out: &mut Vec<Segment>) {
+        if star && context == Context::DoubleQuoted {
+            let separator: String = self.ifs().chars().take(1).collect();
+            out.push(Segment { text: values.join(&separator), kind: SegmentKind::Quoted });
+            return;
+        }
+
+        let kind = match context {
+            Context::DoubleQuoted => SegmentKind::Quoted,
+            _ => SegmentKind::Expanded,
+        };
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                out.push(Segment { text: String::new(), kind: SegmentKind::FieldBreak });
+            }
+            out.push(Segment { text: value.clone(), kind });
+        }
+    }
+
+    fn expand_parts(&mut self, parts: &[WordPart], context: Context) -> io::Result<Vec<Segment>> {
+        let mut segments = Vec::new();
+        for part in parts {
+            match part {
+                WordPart::Literal(text) => {
+                    let kind = match context {
+                        Context::Unquoted => SegmentKind::Literal,
+                        Context::Nested => SegmentKind::Expanded,
+                        Context::DoubleQuoted => SegmentKind::Quoted,
+                    };
+                    segments.push(Segment { text: text.clone(), kind });
+                }
+                WordPart::Quoted(text) => {
+                    segments.push(Segment { text: text.clone(), kind: SegmentKind::Quoted });
+                }
+                WordPart::DoubleQuoted(inner) => {
+                    // An empty pair of quotes still produces an (empty) field, while
+                    // `"$@"` with no positional parameters produces none
+                    if inner.is_empty() {
+                        segments.push(Segment { text: String::new(), kind: SegmentKind::Quoted });
+                    }
+                    segments.extend(self.expand_parts(inner, Context::DoubleQuoted)?);
+                }
+                WordPart::Param(param) => self.expand_parameter(param, context, &mut segments)?,
+                WordPart::Arithmetic(parts) => {
+                    let kind = match context {
+                        Context::DoubleQuoted => SegmentKind::Quoted,
+                        _ => SegmentKind::Expanded,
+                    };
+                    let value = self.arithmetic_expansion(parts)?;
+                    segments.push(Segment { text: value.to_string(), kind });
+                }
+                WordPart::CommandSubst(list) => {
+                    let kind = match context {
+                        Context::DoubleQuoted => SegmentKind::Quoted,
+                        _ => SegmentKind::Expanded,
+                    };
+                    segments.push(Segment { text: self.command_substitution(list)?, kind });
+                }
+                // An array list passed to `declare` and the like is expanded here and
+                // handed over as quoted text, which the builtin reads back
+                WordPart::Array(elements) => {
+                    let elements: Vec<String> = self
+                        .expand_array(elements)?
+                        .into_iter()
+                        .map(|(index, value)| match index {
+                            Some(index) => format!("[{}]={}", quote(&index), quote(&value)),
+                            None => quote(&value),
+                        })
+                        .collect();
+                    segments.push(Segment { text: format!("({})", elements.join(" ")), kind: SegmentKind::Quoted });
+                }
+            }
+        }
+        Ok(segments)
+    }
+
+    /// Expands the text of `$((...))` and evaluates it; an invalid expression aborts
+    /// the command
+    fn arithmetic_expansion(&mut self, parts: &[WordPart]) -> io::Result<i64> {
+        let expr: String = self
+            .expand_parts(parts, Context::DoubleQuoted)?
+            .into_iter()
+            .map(|segment| segment.text)
+            .collect();
+        self.evaluate_arithmetic(&expr)
+            .map_err(|e| io::Error::other(format!("{}: {}", expr.trim(), e)))
+    }
+
+    fn expand_parameter(&mut self, param: &ParamExpansion, context: Context, out: &mut Vec<Segment>) -> io::Result<()> {
+        let kind = match context {
+            Context::DoubleQuoted => SegmentKind::Quoted,
+            _ => SegmentKind::Expanded,
+        };
+        let nested = match context {
+            Context::DoubleQuoted => Context::DoubleQuoted,
+            _ => Context::Nested,
+        };
+        let index = match &param.index {
+            Some(index) => Some(self.expand_subscript(index)?),
+            None => None,
+        };
+        if let ParamOp::Keys = param.op {
+            let keys = self.variables.value(&param.name).map(Value::keys).unwrap_or_default();
+            self.expand_fields(&keys, index.as_deref() == Some("*"), context, out);
+            return Ok(());
+        }
+
+        // `$@`, `$*`, `${name[@]}` and `${name[*]}` expand to a list of values
+        let positional = index.is_none() && (param.name == "@" || param.name == "*");
+        let list = match index.as_deref() {
+            Some(index @ ("@" | "*")) => {
+                let elements = match self.variables.value(&param.name) {
+                    Some(value) => value.indexed_values(),
+                    None => self.lookup_variable(&param.name).into_iter().enumerate().collect(),
+                };
+                Some((elements, index == "*"))
+            }
+            None if positional => {
+                let elements = self.positional_params.iter().cloned().enumerate().map(|(i, value)| (i + 1, value));
+                Some((elements.collect::<Vec<_>>(), param.name == "*"))
+            }
+            _ => None,
+        };
+        if let Some((mut elements, star)) = list {
+            match &param.op {
+                ParamOp::Value => {
+                    let values: Vec<String> = elements.into_iter().map(|(_, value)| value).collect();
+                    self.expand_fields(&values, star, context, out);
+                    return Ok(());
+                }
+                ParamOp::Length => {
+                    out.push(Segment { text: elements.len().to_string(), kind });
+                    return Ok(());
+                }
+                ParamOp::Slice { offset, length } => {
+                    // `${@:0}` starts with `$0`
+                    if positional {
+                        elements.insert(0, (0, self.shell_name.clone()));
+                    }
+                    let values = self.slice_elements(elements, offset, length.as_ref())?;
+                    self.expand_fields(&values, star, context, out);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        let value = match &index {
+            Some(index) => self.lookup_element(&param.name, index)?,
+            None => self.lookup_variable(&param.name),
+        };
+        let checked = matches!(
+            param.op,
+            ParamOp::Value
+                | ParamOp::Length
+                | ParamOp::Slice { .. }
+                | ParamOp::RemovePrefix { .. }
+                | ParamOp::RemoveSuffix { .. }
+                | ParamOp::Replace { .. }
+        );
+        if value.is_none() && checked && self.options.nounset {
+            let name = match &index {
+                Some(index) => format!("{}[{}]", param.name, index),
+                None => param.name.clone(),
+            };
+            return Err(io::Error::other(format!("{}: unbound variable", name)));
+        }
+        let is_unset = |colon: bool| match &value {
+            None => true,
+            Some(value) => colon && value.is_empty(),
+        };
+
+        let text = match &param.op {
+            ParamOp::Value => value.unwrap_or_default(),
+            ParamOp::Length => value.unwrap_or_default().chars().count().to_string(),
+            ParamOp::Keys => unreachable!(),
+            ParamOp::Slice { offset, length } => {
+                let offset = self.arithmetic_expansion(&offset.parts)?;
+                let length = match length {
+                    Some(length) => Some(self.arithmetic_expansion(&length.parts)?),
+                    None => None,
+                };
+                slice_text(&value.unwrap_or_default(), offset, length)?
+            }
+            ParamOp::Default { colon, word } => {
+                if is_unset(*colon) {
+                    out.extend(self.expand_parts(&word.parts, nested)?);
+                    return Ok(());
+                }
+                value.unwrap_or_default()
+            }
+            ParamOp::Assign { colon, word } => {
+                if is_unset(*colon) {
+                    let assigned = self.expand_word(word)?;
+                    self.assign_scalar(&param.name, index.as_deref(), assigned.clone(), false)
+                        .map_err(io::Error::other)?;
+                    assigned
+                } else {
+                    value.unwrap_or_default()
+                }
+            }
+            ParamOp::Error { colon, word } => {
+                if is_unset(*colon) {
+                    let mut message = self.expand_word(word)?;
+                    if message.is_empty() {
+                        message = "parameter null or not set".to_string();
+                    }
+                    return Err(io::Error::other(format!("{}: {}", param.name, message)));
+                }
+                value.unwrap_or_default()
+            }
+            ParamOp::Alternative { colon, word } => {
+                if !is_unset(*colon) {
+                    out.extend(self.expand_parts(&word.parts, nested)?);
+                }
+                return Ok(());
+            }
+            ParamOp::RemovePrefix { longest, pattern } => {
+                let pattern = compile_pattern(&self.expand_pattern(pattern)?);
+                remove_prefix(&value.unwrap_or_default(), &pattern, *longest)
+            }
+            ParamOp::RemoveSuffix { longest, pattern } => {
+                let pattern = compile_pattern(&self.expand_pattern(pattern)?);
+                remove_suffix(&value.unwrap_or_default(), &pattern, *longest)
+            }
+            ParamOp::Replace { mode, pattern, replacement } => {
+                let pattern = compile_pattern(&self.expand_pattern(pattern)?);
+                let replacement = self.expand_word(replacement)?;
+                replace(&value.unwrap_or_default(), &pattern, &replacement, *mode)
+            }
+        };
+
+        out.push(Segment { text, kind });
+        Ok(())
+    }
+}
+
+/// Compiles a glob pattern, falling back to matching it literally if it is malformed
+pub fn compile_pattern(pattern: &str) -> Pattern {
+    Pattern::new(pattern).unwrap_or_else(|_| Pattern::new(&Pattern::escape(pattern)).unwrap())
+}
+
+/// Takes the characters of `${NAME:offset:length}`; a negative offset counts back
+/// from the end, and a negative length stops that many characters before it
+fn slice_text(value: &str, offset: i64, length: Option<i64>) -> io::Result<String> {
+    let chars: Vec<char> = value.chars().collect();
+    let len = chars.len() as i64;
+    let start = if offset < 0 { offset + len } else { offset };
+    if start < 0 || start > len {
+        return Ok(String::new());
+    }
+    let end = match length {
+        Some(length) if length < 0 => length + len,
+        Some(length) => (start + length).min(len),
+        None => len,
+    };
+    if end < start {
+        return Err(io::Error::other(format!("{}: substring expression < 0", length.unwrap_or_default())));
+    }
+    Ok(chars[start as usize..end as usize].iter().collect())
+}
+
+/// Byte offsets of every character boundary in `s`, including both ends
+fn boundaries(s: &str) -> Vec<usize> {
+    s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len())).collect()
+}
+
+fn remove_prefix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let mut ends = boundaries(value);
+    if longest {
+        ends.reverse();
+    }
+    ends.into_iter()
+        .find(|&end| pattern.matches(&value[..end]))
+        .map(|end| value[end..].to_string())
+        .unwrap_or_else(|| value.to_string())
+}
+
+fn remove_suffix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let mut starts = boundaries(value);
+    if !longest {
+        starts.reverse();
+    }
+    starts
+        .into_iter()
+        .find(|&start| pattern.matches(&value[start..]))
+        .map(|start| value[..start].to_string())
+        .unwrap_or_else(|| value.to_string())
+}
+
+/// Finds the longest match of `pattern` starting at `start`
+fn longest_match_at(value: &str, pattern: &Pattern, start: usize, bounds: &[usize]) -> Option<usize> {
+    bounds
+        .iter()
+        .rev()
+        .copied()
+        .filter(|&end| end >= start)
+        .find(|&end| pattern.matches(&value[start..end]))
+}
+
+fn replace(value: &str, pattern: &Pattern, replacement: &str, mode: ReplaceMode) -> String {
+    let bounds = boundaries(value);
+    match mode {
+        ReplaceMode::Prefix => match longest_match_at(value, pattern, 0, &bounds) {
+            Some(end) => format!("{}{}", replacement, &value[end..]),
+            None => value.to_string(),
+        },
+        ReplaceMode::Suffix => match bounds.iter().find(|&&start| pattern.matches(&value[start..])) {
+            Some(&start) => format!("{}{}", &value[..start], replacement),
+            None => value.to_string(),
+        },
+        ReplaceMode::First | ReplaceMode::All => {
+            let mut result = String::new();
+            let mut pos = 0;
+            let mut i = 0;
+            while i < bounds.len() {
+                let start = bounds[i];
+                if start < pos {
+                    i += 1;
+                    continue;
+                }
+                match longest_match_at(value, pattern, start, &bounds) {
+                    // Empty matches would replace between every character; skip them
+                    Some(end) if end > start => {
+                        result.push_str(&value[pos..start]);
+                        result.push_str(replacement);
+                        pos = end;
+                        if mode == ReplaceMode::First {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+            result.push_str(&value[pos..]);
+            result
+        }
+    }
+}
+
+/// A field produced by field splitting
+struct Field {
+    text: String,
+    /// The text as a glob pattern, with quoted characters escaped
+    pattern: String,
+    /// Whether an unquoted `*`, `?` or `[` makes the field subject to pathname expansion
+    has_glob: bool,
+}
+
+/// Accumulates the fields produced by field splitting
+#[derive(Default)]
+struct FieldBuilder {
+    fields: Vec<Field>,
+    current: String,
+    pattern: String,
+    has_glob: bool,
+    /// Whether the current field exists even if it is empty (e.g. `""`)
+    started: bool,
+    /// Whether the last delimiter seen was IFS whitespace
+    after_whitespace: bool,
+}
+
+impl FieldBuilder {
+    fn push(&mut self, text: &str, quoted: bool) {
+        self.current.push_str(text);
+        if quoted {
+            self.pattern.push_str(&Pattern::escape(text));
+        } else {
+            self.pattern.push_str(text);
+            self.has_glob |= text.contains(['*', '?', '[']);
+        }
+        self.started = true;
+        self.after_whitespace = false;
+    }
+
+    fn end_field(&mut self) {
+        self.fields.push(Field {
+            text: std::mem::take(&mut self.current),
+            pattern: std::mem::take(&mut self.pattern),
+            has_glob: std::mem::take(&mut self.has_glob),
+        });
+        self.started = false;
+    }
+
+    /// Appends expansion output, splitting it on the characters of `ifs`
+    fn push_split(&mut self, text: &str, ifs: &str) {
+        for c in text.chars() {
+            if !ifs.contains(c) {
+                self.push(c.encode_utf8(&mut [0; 4]), false);
+            } else if c == ' ' || c == '\t' || c == '\n' {
+                if self.started {
+                    self.end_field();
+                    self.after_whitespace = true;
+                }
+            } else {
+                if self.started || !self.after_whitespace {
+                    self.end_field();
+                }
+                self.after_whitespace = false;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        if self.started {
+            self.end_field();
+        }
+        self.fields
+    }
+}
+
+fn split_fields(segments: &[Segment], ifs: &str) -> Vec<Field> {
+    let mut builder = FieldBuilder::default();
+    for segment in segments {
+        match segment.kind {
+            SegmentKind::Literal => builder.push(&segment.text, false),
+            SegmentKind::Quoted => builder.push(&segment.text, true),
+            SegmentKind::Expanded => builder.push_split(&segment.text, ifs),
+            SegmentKind::FieldBreak => {
+                if builder.started {
+                    builder.end_field();
+                }
+            }
+        }
+    }
+    builder.finish()
+}
+
+/// Returns the text of a pattern component that contains no unescaped `*`, `?` or
+/// `[`, undoing the escaping done by `Pattern::escape`
+fn literal_component(component: &str) -> Option<String> {
+    let mut literal = String::new();
+    let mut rest = component;
+    while let Some(c) = rest.chars().next() {
+        if let Some(escaped) = ["[*]", "[?]", "[[]", "[]]"].iter().find(|e| rest.starts_with(**e)) {
+            literal.push_str(&escaped[1..2]);
+            rest = &rest[3..];
+        } else if matches!(c, '*' | '?' | '[') {
+            return None;
+        } else {
+            literal.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Some(literal)
+}
+
+/// Looks up the home directory of a user account
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+
+    let name = CString::new(user).ok()?;
+    // Words are expanded in several threads at once, so the record goes in a buffer
+    // of our own rather than the static one `getpwnam` uses
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: `passwd` is plain data, for which all zeros is a valid value
+        let mut record: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut entry = std::ptr::null_mut();
+        // SAFETY: `getpwnam_r` fills in `record` with pointers into `buffer`, which
+        // outlives their use below
+        let error = unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut record, buffer.as_mut_ptr(), buffer.len(), &mut entry)
+        };
+        match error {
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            0 if !entry.is_null() && !record.pw_dir.is_null() => {
+                // SAFETY: `pw_dir` points to a string in `buffer`
+                return Some(unsafe { CStr::from_ptr(record.pw_dir) }.to_string_lossy().into_owned());
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn user_home(_user: &str) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(text: &str, kind: SegmentKind) -> Segment {
+        Segment { text: text.to_string(), kind }
+    }
+
+    fn texts(fields: Vec<Field>) -> Vec<String> {
+        fields.into_iter().map(|field| field.text).collect()
+    }
+
+    #[test]
+    fn slice_offsets_and_lengths() {
+        assert_eq!(slice_text("hello", 1, Some(3)).unwrap(), "ell");
+        assert_eq!(slice_text("hello", 1, None).unwrap(), "ello");
+        assert_eq!(slice_text("hello", 2, Some(10)).unwrap(), "llo");
+        assert_eq!(slice_text("hello", -3, None).unwrap(), "llo");
+        assert_eq!(slice_text("hello", 1, Some(-1)).unwrap(), "ell");
+        assert_eq!(slice_text("hello", 10, None).unwrap(), "");
+        assert_eq!(slice_text("hello", -10, None).unwrap(), "");
+        assert_eq!(slice_text("héllo", 1, Some(2)).unwrap(), "él");
+        assert!(slice_text("hello", 3, Some(-3)).is_err());
+    }
+
+    #[test]
+    fn prefix_removal() {
+        let pattern = compile_pattern("*/");
+        assert_eq!(remove_prefix("a/b/c", &pattern, false), "b/c");
+        assert_eq!(remove_prefix("a/b/c", &pattern, true), "c");
+        assert_eq!(remove_prefix("abc", &pattern, true), "abc");
+    }
+
+    #[test]
+    fn suffix_removal() {
+        let pattern = compile_pattern(".*");
+        assert_eq!(remove_suffix("file.tar.gz", &pattern, false), "file.tar");
+        assert_eq!(remove_suffix("file.tar.gz", &pattern, true), "file");
+        assert_eq!(remove_suffix("file", &pattern, false), "file");
+    }
+
+    #[test]
+    fn replacement_modes() {
+        let pattern = compile_pattern("an");
+        assert_eq!(replace("banana", &pattern, "X", ReplaceMode::First), "bXana");
+        assert_eq!(replace("banana", &pattern, "X", ReplaceMode::All), "bXXa");
+        assert_eq!(replace("banana", &compile_pattern("ba"), "X", ReplaceMode::Prefix), "Xnana");
+        assert_eq!(replace("banana", &compile_pattern("na"), "X", ReplaceMode::Suffix), "banaX");
+        assert_eq!(replace("banana", &compile_pattern("na"), "X", ReplaceMode::Prefix), "banana");
+        // Matches are as long as possible, and empty matches replace nothing
+        assert_eq!(replace("banana", &compile_pattern("a*a"), "X", ReplaceMode::First), "bX");
+        assert_eq!(replace("abc", &compile_pattern("x*"), "X", ReplaceMode::All), "abc");
+    }
+
+    #[test]
+    fn splitting_on_whitespace() {
+        let fields = split_fields(&[segment("  a \t b\n", SegmentKind::Expanded)], " \t\n");
+        assert_eq!(texts(fields), ["a", "b"]);
+        assert!(split_fields(&[segment("", SegmentKind::Expanded)], " \t\n").is_empty());
+        assert_eq!(texts(split_fields(&[segment("a b", SegmentKind::Expanded)], "")), ["a b"]);
+    }
+
+    #[test]
+    fn splitting_on_other_delimiters() {
+        let fields = split_fields(&[segment("a::b:", SegmentKind::Expanded)], ":");
+        assert_eq!(texts(fields), ["a", "", "b"]);
+        let fields = split_fields(&[segment("a : b", SegmentKind::Expanded)], " :");
+        assert_eq!(texts(fields), ["a", "b"]);
+    }
+
+    #[test]
+    fn quoted_and_literal_text_is_not_split() {
+        let segments = [
+            segment("x y", SegmentKind::Quoted),
+            segment("a b", SegmentKind::Expanded),
+            segment("c d", SegmentKind::Literal),
+        ];
+        assert_eq!(texts(split_fields(&segments, " ")), ["x ya", "bc d"]);
+        let fields = split_fields(&[segment("", SegmentKind::Quoted)], " ");
+        assert_eq!(texts(fields), [""]);
+    }
+
+    #[test]
+    fn field_breaks_and_glob_patterns() {
+        let segments = [
+            segment("a", SegmentKind::Expanded),
+            segment("", SegmentKind::FieldBreak),
+            segment("*", SegmentKind::Quoted),
+            segment("", SegmentKind::FieldBreak),
+            segment("*", SegmentKind::Expanded),
+        ];
+        let fields = split_fields(&segments, " ");
+        assert_eq!(fields.iter().map(|field| field.has_glob).collect::<Vec<_>>(), [false, false, true]);
+        assert_eq!(fields[1].pattern, "[*]");
+        assert_eq!(texts(fields), ["a", "*", "*"]);
+    }
+}
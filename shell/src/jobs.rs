@@ -0,0 +1,775 @@
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ast::AndOr;
+use crate::command_execution::{spawn_thread, Stage, StageState};
+use crate::error::{BuiltinError, BuiltinResult};
+use crate::shell::Shell;
+#[cfg(unix)]
+use crate::signals::STOP_SIGNALS;
+use crate::signals::{self, INTERRUPTED_STATUS};
+
+/// Status of a job stopped with Ctrl-Z, as if SIGTSTP had killed it
+#[cfg(unix)]
+pub const STOPPED_STATUS: i32 = 128 + libc::SIGTSTP;
+#[cfg(not(unix))]
+pub const STOPPED_STATUS: i32 = 148;
+
+/// How often a job with stages running in threads is checked on, since threads
+/// cannot be waited for together with processes
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The next ID for a background job that has no process for `$!` to name. These
+/// start above the largest process ID Linux hands out
+static NEXT_SYNTHETIC_PID: AtomicU32 = AtomicU32::new((1 << 22) + 1);
+
+/// The terminal the interactive shell controls, and the shell's own process group
+/// that gets it back whenever a foreground job finishes or stops
+#[derive(Debug, Clone, Copy)]
+pub struct Terminal {
+    fd: i32,
+    shell_pgid: i32,
+}
+
+impl Terminal {
+    /// Takes control of the terminal on stdin: the shell waits to be in the
+    /// foreground, moves into a process group of its own and ignores the stop
+    /// signals. Returns `None` when stdin is not a terminal
+    pub fn init() -> Option<Terminal> {
+        #[cfg(unix)]
+        unsafe {
+            if libc::isatty(0) == 0 {
+                return None;
+            }
+            // Started in the background, as in `shell &`: stop until brought forward
+            loop {
+                let pgid = libc::getpgrp();
+                let foreground = libc::tcgetpgrp(0);
+                if foreground == -1 || foreground == pgid {
+                    break;
+                }
+                libc::kill(-pgid, libc::SIGTTIN);
+            }
+            for signal in STOP_SIGNALS {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            // Fails harmlessly for a session leader, which already leads its group
+            libc::setpgid(0, 0);
+            let shell_pgid = libc::getpgrp();
+            libc::tcsetpgrp(0, shell_pgid);
+            // A descriptor of its own, out of the way of redirections and closed in
+            // commands once they start
+            let fd = libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 10);
+            (fd >= 0).then_some(Terminal { fd, shell_pgid })
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Makes `pgid` the foreground process group
+    pub fn give_to(&self, pgid: u32) {
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetpgrp(self.fd, pgid as libc::pid_t);
+        }
+        #[cfg(not(unix))]
+        let _ = pgid;
+    }
+
+    /// Reads a line the user types into `line`, returning the number of bytes read.
+    /// Ctrl-C makes it fail with `Interrupted`, discarding what was typed
+    pub fn read_line(&self, line: &mut String) -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        #[cfg(unix)]
+        loop {
+            // The interrupt only throws away the line being typed
+            if let Err(e) = signals::wait_for_input(self.fd) {
+                signals::take_interrupt();
+                return Err(e);
+            }
+            let mut buffer = [0u8; 1024];
+            let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            bytes.extend_from_slice(&buffer[..read as usize]);
+            if read == 0 || bytes.ends_with(b"\n") {
+                break;
+            }
+        }
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(bytes.len())
+    }
+
+    /// Puts the shell back in the foreground
+    pub fn reclaim(&self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetpgrp(self.fd, self.shell_pgid);
+        }
+    }
+}
+
+/// Prepares a process of a job: a foreground job takes the terminal before the stop
+/// signals the shell ignores get their defaults back
+#[cfg(unix)]
+fn prepare_child(cmd: &mut Command, terminal: Option<Terminal>) {
+    use std::os::unix::process::CommandExt;
+    let fd = terminal.map(|terminal| terminal.fd);
+    // Only async-signal-safe calls are made between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(fd) = fd {
+                libc::tcsetpgrp(fd, libc::getpgrp());
+            }
+            for signal in STOP_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Checks on a child with `waitpid`, which unlike `Child::wait` also reports a
+/// process that has stopped
+#[cfg(unix)]
+pub fn wait_process(pid: u32, block: bool) -> io::Result<StageState> {
+    let flags = libc::WUNTRACED | if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    loop {
+        match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, flags) } {
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            0 => return Ok(StageState::Running),
+            _ if libc::WIFSTOPPED(status) => return Ok(StageState::Stopped),
+            _ if libc::WIFSIGNALED(status) => return Ok(StageState::Done(128 + libc::WTERMSIG(status))),
+            _ => return Ok(StageState::Done(libc::WEXITSTATUS(status))),
+        }
+    }
+}
+
+/// Whether a process in the group has stopped. The stop is left to be collected by
+/// whoever waits for the process, such as a pipeline stage running in a thread
+#[cfg(unix)]
+fn group_stopped(pgid: u32) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WSTOPPED | libc::WNOHANG | libc::WNOWAIT;
+    let found = unsafe { libc::waitid(libc::P_PGID, pgid as libc::id_t, &mut info, flags) };
+    found == 0 && unsafe { info.si_pid() } != 0
+}
+
+/// The process group shared by the processes of one job. Its ID is that of the first
+/// process started in it
+#[derive(Debug, Clone, Default)]
+pub struct ProcessGroup {
+    id: Arc<Mutex<Option<u32>>>,
+    /// Set for a foreground job, which takes the terminal when its group is created
+    terminal: Option<Terminal>,
+    /// Held by the shell while it starts a background job, so that the job's threads
+    /// wait for its notice to be printed
+    starting: Arc<RwLock<()>>,
+}
+
+impl ProcessGroup {
+    pub fn foreground(terminal: Option<Terminal>) -> ProcessGroup {
+        ProcessGroup { id: Arc::default(), terminal, starting: Arc::default() }
+    }
+
+    /// Blocks until the shell has finished starting the job
+    fn wait_until_started(&self) {
+        drop(self.starting.read().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Whether the group is a foreground job, which Ctrl-C interrupts
+    pub fn is_foreground(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        *self.id.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts `cmd` in the group, creating the group if this is its first process
+    pub fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+        let mut pgid = self.id.lock().unwrap_or_else(PoisonError::into_inner);
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            prepare_child(cmd, self.terminal);
+            if let Some(id) = *pgid {
+                cmd.process_group(id as i32);
+                match cmd.spawn() {
+                    Ok(child) => return Ok(child),
+                    // Once every earlier process has been reaped the group no longer
+                    // exists, so the command starts a new one
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            cmd.process_group(0);
+            let child = cmd.spawn()?;
+            *pgid = Some(child.id());
+            // The child does this too; whichever runs first wins the race against
+            // the command reading the terminal
+            if let Some(terminal) = self.terminal {
+                terminal.give_to(child.id());
+            }
+            Ok(child)
+        }
+        #[cfg(not(unix))]
+        {
+            let child = cmd.spawn()?;
+            pgid.get_or_insert(child.id());
+            Ok(child)
+        }
+    }
+
+    /// Sends SIGCONT to every process of the group
+    fn resume(&self) {
+        #[cfg(unix)]
+        if let Some(pgid) = self.id() {
+            unsafe {
+                libc::kill(-(pgid as libc::pid_t), libc::SIGCONT);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    /// Finished with this exit status
+    Done(i32),
+}
+
+/// A command started with `&`, or a foreground command that was stopped
+pub struct Job {
+    pub id: usize,
+    /// The command as it was written
+    pub command: String,
+    pub state: JobState,
+    /// When the job was last started or stopped, which decides the current job
+    changed: Instant,
+    group: ProcessGroup,
+    stages: Vec<Stage>,
+    /// The ID `$!` was given for the job, if it was started in the background
+    background_pid: Option<u32>,
+}
+
+impl Job {
+    fn new(command: &str, group: ProcessGroup, stages: Vec<Stage>) -> Job {
+        Job {
+            id: 0,
+            command: command.to_string(),
+            state: JobState::Running,
+            changed: Instant::now(),
+            group,
+            stages,
+            background_pid: None,
+        }
+    }
+
+    /// Checks on one stage; a stage that cannot be waited for is treated as failed
+    fn poll_stage(stage: &mut Stage, block: bool) -> StageState {
+        stage.poll(block).unwrap_or_else(|_| {
+            *stage = Stage::Finished(1);
+            StageState::Done(1)
+        })
+    }
+
+    /// Collects the stages that have finished without blocking and updates the
+    /// state. A stopped job stays stopped until it is continued
+    fn poll(&mut self) -> JobState {
+        let mut status = Some(0);
+        let mut stopped = false;
+        for stage in &mut self.stages {
+            match Job::poll_stage(stage, false) {
+                StageState::Done(stage_status) => status = status.map(|_| stage_status),
+                StageState::Stopped => {
+                    stopped = true;
+                    status = None;
+                }
+                StageState::Running => status = None,
+            }
+        }
+        #[cfg(unix)]
+        if !stopped && self.stages.iter().any(|stage| matches!(stage, Stage::Thread(_))) {
+            stopped = self.group.id().is_some_and(group_stopped);
+        }
+
+        if let Some(status) = status {
+            self.state = JobState::Done(status);
+        } else if stopped && self.state == JobState::Running {
+            self.state = JobState::Stopped;
+            self.changed = Instant::now();
+        }
+        self.state
+    }
+
+    /// Blocks until the job finishes or stops
+    fn wait(&mut self) -> JobState {
+        loop {
+            let state = self.poll();
+            if state != JobState::Running {
+                return state;
+            }
+            // A process can be waited for directly, but a thread, and the processes
+            // it waits for itself, can only be checked on
+            if self.stages.iter().any(|stage| matches!(stage, Stage::Thread(_))) {
+                thread::sleep(POLL_INTERVAL);
+            } else if let Some(stage) = self.stages.iter_mut().find(|stage| matches!(stage, Stage::Process(_))) {
+                if Job::poll_stage(stage, true) == StageState::Stopped {
+                    self.state = JobState::Stopped;
+                    self.changed = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// Continues a stopped job
+    fn resume(&mut self) {
+        self.group.resume();
+        self.state = JobState::Running;
+        self.changed = Instant::now();
+    }
+
+    /// Exit status of each stage once the job is done
+    fn statuses(&self) -> Vec<i32> {
+        self.stages
+            .iter()
+            .map(|stage| match stage {
+                Stage::Finished(status) => *status,
+                _ => 1,
+            })
+            .collect()
+    }
+
+    /// The ID `$!` was given, else that of the job's process group, or of its last
+    /// process if it has none
+    fn pid(&self) -> Option<u32> {
+        self.background_pid.or_else(|| self.group.id()).or_else(|| self.stages.iter().rev().find_map(Stage::pid))
+    }
+
+    /// Whether `pid` names the job, its process group or one of its processes
+    fn has_pid(&self, pid: u32) -> bool {
+        self.background_pid == Some(pid)
+            || self.group.id() == Some(pid)
+            || self.stages.iter().any(|stage| stage.pid() == Some(pid))
+    }
+
+    /// Describes the state the way the job table shows it
+    pub fn state_text(&self) -> String {
+        match self.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(0) => "Done".to_string(),
+            JobState::Done(status) => signal_description(status).unwrap_or_else(|| format!("Exit {}", status)),
+        }
+    }
+}
+
+/// Names the signal behind an exit status above 128, such as `Terminated`
+fn signal_description(status: i32) -> Option<String> {
+    #[cfg(unix)]
+    if status > 128 {
+        let description = unsafe { libc::strsignal(status - 128) };
+        if !description.is_null() {
+            return Some(unsafe { std::ffi::CStr::from_ptr(description) }.to_string_lossy().into_owned());
+        }
+    }
+    let _ = status;
+    None
+}
+
+/// The shell's jobs in order of their IDs. A copy of the shell, such as a subshell,
+/// starts with no jobs of its own
+#[derive(Default)]
+pub struct JobTable(Vec<Job>);
+
+impl Clone for JobTable {
+    fn clone(&self) -> JobTable {
+        JobTable::default()
+    }
+}
+
+impl Deref for JobTable {
+    type Target = Vec<Job>;
+
+    fn deref(&self) -> &Vec<Job> {
+        &self.0
+    }
+}
+
+impl DerefMut for JobTable {
+    fn deref_mut(&mut self) -> &mut Vec<Job> {
+        &mut self.0
+    }
+}
+
+impl JobTable {
+    /// Adds a job, giving it the next free ID unless it already has one
+    fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.last().map_or(1, |last| last.id + 1);
+        }
+        let id = job.id;
+        let index = self.partition_point(|other| other.id < id);
+        self.insert(index, job);
+        id
+    }
+}
+
+impl Shell {
+    /// Starts an and-or list in the background and adds it to the job table. Like a
+    /// subshell it runs on a copy of the shell, with its processes in a new group.
+    /// The job's threads wait until its notice has been printed
+    pub fn start_background(&mut self, and_or: &AndOr, text: &str) -> io::Result<()> {
+        let group = ProcessGroup::default();
+        let starting = Arc::clone(&group.starting);
+        let held = starting.write().unwrap_or_else(PoisonError::into_inner);
+        let mut sub = self.thread_copy();
+        sub.process_group = Some(group.clone());
+
+        let (stages, started) = if and_or.rest.is_empty() && !and_or.first.negated {
+            sub.start_pipeline(&and_or.first.commands)
+        } else {
+            let and_or = and_or.clone();
+            let thread = spawn_thread(move || {
+                sub.wait_for_job_start();
+                sub.execute_and_or(&and_or).unwrap_or_else(|e| {
+                    let _ = writeln!(sub.stderr(), "{}", e);
+                    1
+                })
+            });
+            match thread {
+                Ok(handle) => (vec![Stage::Thread(handle)], Ok(())),
+                Err(e) => (Vec::new(), Err(e)),
+            }
+        };
+        if stages.is_empty() {
+            return started;
+        }
+
+        // A job without a process of its own yet still gets an ID for `$!` and `wait`
+        let pid = stages
+            .iter()
+            .rev()
+            .find_map(Stage::pid)
+            .or_else(|| group.id())
+            .unwrap_or_else(|| NEXT_SYNTHETIC_PID.fetch_add(1, Ordering::Relaxed));
+        self.last_background_pid = Some(pid);
+        let mut job = Job::new(text, group, stages);
+        job.background_pid = Some(pid);
+        let id = self.jobs.add(job);
+        let _ = writeln!(self.stderr(), "[{}] {}", id, pid);
+        drop(held);
+        self.last_status = 0;
+        started
+    }
+
+    /// Blocks a thread of a background job until the shell has started the job
+    pub fn wait_for_job_start(&self) {
+        if let Some(group) = &self.process_group {
+            group.wait_until_started();
+        }
+    }
+
+    /// Runs a command as a foreground job: its processes get a group of their own that
+    /// is handed the terminal. `start` starts the stages while the group is the
+    /// shell's. Returns the exit status of each stage, or `STOPPED_STATUS` alone if
+    /// the job was stopped and put in the job table
+    pub fn run_foreground(
+        &mut self,
+        text: &str,
+        start: impl FnOnce(&mut Shell) -> (Vec<Stage>, io::Result<()>),
+    ) -> io::Result<Vec<i32>> {
+        let group = ProcessGroup::foreground(self.terminal);
+        let saved = self.process_group.replace(group.clone());
+        let (stages, started) = start(self);
+        self.process_group = saved;
+        let statuses = self.wait_for_job(Job::new(text, group, stages));
+        started.map(|()| statuses)
+    }
+
+    /// Continues a job if it is stopped and waits for it with the terminal handed to
+    /// it. A job that stops goes back into the job table. A job killed by Ctrl-C
+    /// interrupts the shell as well, ending the command line it was part of
+    fn wait_for_job(&mut self, mut job: Job) -> Vec<i32> {
+        if let (Some(terminal), Some(pgid)) = (self.terminal, job.group.id()) {
+            terminal.give_to(pgid);
+        }
+        if job.state == JobState::Stopped {
+            job.resume();
+        }
+        signals::set_foreground(job.group.id());
+        let state = job.wait();
+        signals::set_foreground(None);
+        if let Some(terminal) = self.terminal {
+            terminal.reclaim();
+        }
+        if state != JobState::Stopped {
+            let statuses = job.statuses();
+            if statuses.contains(&INTERRUPTED_STATUS) {
+                signals::interrupt();
+            }
+            // A job killed by Ctrl-\ is reported, which also ends the line the terminal
+            // echoed `^\` on
+            #[cfg(unix)]
+            if statuses.contains(&(128 + libc::SIGQUIT)) {
+                let _ = writeln!(self.stderr(), "{}", signal_description(128 + libc::SIGQUIT).unwrap_or_default());
+            }
+            return statuses;
+        }
+
+        let command = job.command.clone();
+        let id = self.jobs.add(job);
+        let _ = writeln!(self.stderr(), "\n[{}]+  {:<24}{}", id, "Stopped", command);
+        vec![STOPPED_STATUS]
+    }
+
+    /// Indexes of the current job, `%+`, and the previous one, `%-`: the most
+    /// recently stopped jobs come first, then the most recently started
+    fn current_jobs(&self) -> (Option<usize>, Option<usize>) {
+        let mut order: Vec<usize> = (0..self.jobs.len()).collect();
+        order.sort_by_key(|&index| {
+            let job = &self.jobs[index];
+            (job.state == JobState::Stopped, job.changed)
+        });
+        (order.pop(), order.pop())
+    }
+
+    /// `+` marks the current job and `-` the previous one
+    pub fn job_marker(&self, index: usize) -> char {
+        match self.current_jobs() {
+            (Some(current), _) if current == index => '+',
+            (_, Some(previous)) if previous == index => '-',
+            _ => ' ',
+        }
+    }
+
+    /// Finds the job a job spec names: `%N`, `%+` or `%%` for the current job, `%-`
+    /// for the previous one, `%string` for the one whose command starts with
+    /// `string` and `%?string` for the one whose command contains it
+    fn find_job(&self, spec: &str) -> Result<usize, BuiltinError> {
+        let no_such_job = |name: &str| BuiltinError::InvalidArgument(format!("{}: no such job", name));
+        let name = spec.strip_prefix('%').unwrap_or(spec);
+        let (current, previous) = self.current_jobs();
+        let found = match name {
+            "" | "+" | "%" => return current.ok_or_else(|| no_such_job("current")),
+            "-" => previous,
+            _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+                let id = name.parse().map_err(|_| no_such_job(spec))?;
+                self.jobs.iter().position(|job| job.id == id)
+            }
+            _ => {
+                let matches: Vec<usize> = match name.strip_prefix('?') {
+                    Some(text) => (0..self.jobs.len()).filter(|&i| self.jobs[i].command.contains(text)).collect(),
+                    None => (0..self.jobs.len()).filter(|&i| self.jobs[i].command.starts_with(name)).collect(),
+                };
+                if matches.len() > 1 {
+                    return Err(BuiltinError::InvalidArgument(format!("{}: ambiguous job spec", spec)));
+                }
+                matches.first().copied()
+            }
+        };
+        found.ok_or_else(|| no_such_job(spec))
+    }
+
+    /// Formats a line of the job table; `with_pid` adds the process ID as `jobs -l` does
+    fn job_line(&self, index: usize, with_pid: bool) -> String {
+        let job = &self.jobs[index];
+        let pid = match job.pid().filter(|_| with_pid) {
+            Some(pid) => format!(" {} ", pid),
+            None => "  ".to_string(),
+        };
+        let background = if job.state == JobState::Running { " &" } else { "" };
+        format!("[{}]{}{}{:<24}{}{}", job.id, self.job_marker(index), pid, job.state_text(), job.command, background)
+    }
+
+    /// Reports jobs that have stopped or finished since the last call and removes the
+    /// finished ones from the job table. Called before each prompt
+    pub fn notify_jobs(&mut self) {
+        let mut index = 0;
+        while index < self.jobs.len() {
+            let before = self.jobs[index].state;
+            let state = self.jobs[index].poll();
+            if state == before {
+                index += 1;
+                continue;
+            }
+            let line = self.job_line(index, false);
+            let _ = writeln!(self.stderr(), "{}", line);
+            if let JobState::Done(_) = state {
+                self.jobs.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// `jobs [-lp] [jobspec...]` lists jobs and their state; `-l` adds process IDs and
+    /// `-p` prints only those. Finished jobs are reported once and then removed
+    pub fn jobs_cmd(&mut self, args: &[String]) -> BuiltinResult {
+        let mut with_pid = false;
+        let mut pid_only = false;
+        let mut specs = Vec::new();
+        for arg in args {
+            match arg.strip_prefix('-').filter(|flags| !flags.is_empty()) {
+                Some(flags) => {
+                    for flag in flags.chars() {
+                        match flag {
+                            'l' => with_pid = true,
+                            'p' => pid_only = true,
+                            _ => return Err(BuiltinError::Usage(format!("-{}: invalid option", flag))),
+                        }
+                    }
+                }
+                None => specs.push(arg),
+            }
+        }
+
+        for job in self.jobs.iter_mut() {
+            job.poll();
+        }
+        let indexes = if specs.is_empty() {
+            (0..self.jobs.len()).collect()
+        } else {
+            specs.iter().map(|spec| self.find_job(spec)).collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut out = self.stdout();
+        for &index in &indexes {
+            if pid_only {
+                if let Some(pid) = self.jobs[index].pid() {
+                    writeln!(out, "{}", pid)?;
+                }
+            } else {
+                writeln!(out, "{}", self.job_line(index, with_pid))?;
+            }
+        }
+        let mut index = 0;
+        self.jobs.retain(|job| {
+            let listed = indexes.contains(&index);
+            index += 1;
+            !(listed && matches!(job.state, JobState::Done(_)))
+        });
+        Ok(0)
+    }
+
+    /// `fg [jobspec]` continues a job in the foreground and waits for it
+    pub fn fg(&mut self, args: &[String]) -> BuiltinResult {
+        let index = self.find_job(args.first().map_or("%+", String::as_str))?;
+        let job = self.jobs.remove(index);
+        writeln!(self.stdout(), "{}", job.command)?;
+        let statuses = self.wait_for_job(job);
+        Ok(statuses.last().copied().unwrap_or(0))
+    }
+
+    /// `bg [jobspec...]` continues stopped jobs in the background
+    pub fn bg(&mut self, args: &[String]) -> BuiltinResult {
+        let current = ["%+".to_string()];
+        let specs = if args.is_empty() { &current[..] } else { args };
+        for spec in specs {
+            let index = self.find_job(spec)?;
+            if self.jobs[index].state != JobState::Stopped {
+                writeln!(self.stderr(), "bg: job {} already in background", self.jobs[index].id)?;
+                continue;
+            }
+            self.jobs[index].resume();
+            let marker = self.job_marker(index);
+            let job = &self.jobs[index];
+            writeln!(self.stdout(), "[{}]{} {} &", job.id, marker, job.command)?;
+        }
+        Ok(0)
+    }
+
+    /// `wait [jobspec|pid...]` waits for the given jobs, or for every running job, and
+    /// returns the status of the last one. Jobs that finish leave the job table
+    pub fn wait_cmd(&mut self, args: &[String]) -> BuiltinResult {
+        if args.is_empty() {
+            for job in self.jobs.iter_mut().filter(|job| job.state == JobState::Running) {
+                job.wait();
+            }
+            self.jobs.retain(|job| !matches!(job.state, JobState::Done(_)));
+            return Ok(0);
+        }
+
+        let mut status = 0;
+        for arg in args {
+            let index = if arg.starts_with('%') {
+                match self.find_job(arg) {
+                    Ok(index) => index,
+                    Err(e) => {
+                        writeln!(self.stderr(), "wait: {}", e)?;
+                        status = 127;
+                        continue;
+                    }
+                }
+            } else {
+                let Ok(pid) = arg.parse::<u32>() else {
+                    return Err(BuiltinError::Usage(format!("`{}': not a pid or valid job spec", arg)));
+                };
+                let found = self.jobs.iter().position(|job| job.has_pid(pid));
+                let Some(index) = found else {
+                    writeln!(self.stderr(), "wait: pid {} is not a child of this shell", pid)?;
+                    status = 127;
+                    continue;
+                };
+                index
+            };
+            status = match self.jobs[index].wait() {
+                JobState::Done(job_status) => {
+                    self.jobs.remove(index);
+                    job_status
+                }
+                _ => STOPPED_STATUS,
+            };
+        }
+        Ok(status)
+    }
+
+    /// `disown [-ar] [jobspec...]` removes jobs from the job table, the current one
+    /// by default; `-a` removes every job and `-r` every running one. Their processes
+    /// keep running but are no longer reported or waited for
+    pub fn disown(&mut self, args: &[String]) -> BuiltinResult {
+        let (flags, specs): (Vec<&String>, Vec<&String>) =
+            args.iter().partition(|arg| arg.starts_with('-') && arg.len() > 1);
+        let mut all = false;
+        let mut running = false;
+        for flag in flags.iter().flat_map(|flags| flags[1..].chars()) {
+            match flag {
+                'a' => all = true,
+                'r' => running = true,
+                _ => return Err(BuiltinError::Usage(format!("-{}: invalid option", flag))),
+            }
+        }
+
+        if specs.is_empty() && (all || running) {
+            self.jobs.retain(|job| running && !all && job.state != JobState::Running);
+            return Ok(0);
+        }
+        let current = "%+".to_string();
+        let specs = if specs.is_empty() { vec![&current] } else { specs };
+        let mut ids = Vec::new();
+        for spec in specs {
+            let index = self.find_job(spec)?;
+            ids.push(self.jobs[index].id);
+        }
+        self.jobs.retain(|job| !ids.contains(&job.id));
+        Ok(0)
+    }
+}
@@ -0,0 +1,725 @@
+use crate::ast::{ParamExpansion, ParamOp, ReplaceMode, Span, Word, WordPart};
+use crate::parser::{self, is_valid_name, ParseError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Pipe,
+    OrIf,
+    Amp,
+    AndIf,
+    Semi,
+    DSemi,
+    LParen,
+    RParen,
+    Less,
+    Great,
+    DGreat,
+    LessAnd,
+    GreatAnd,
+    LessGreat,
+    Clobber,
+    AndGreat,
+    AndDGreat,
+    DLess,
+    DLessDash,
+    TLess,
+    SemiAnd,
+    DSemiAnd,
+}
+
+/// Operator spellings, longest first so that matching is greedy
+const OPERATORS: &[(&str, Operator)] = &[
+    ("&>>", Operator::AndDGreat),
+    ("<<<", Operator::TLess),
+    (";;&", Operator::DSemiAnd),
+    ("<<-", Operator::DLessDash),
+    ("&&", Operator::AndIf),
+    ("||", Operator::OrIf),
+    (";;", Operator::DSemi),
+    (";&", Operator::SemiAnd),
+    ("<<", Operator::DLess),
+    (">>", Operator::DGreat),
+    ("<&", Operator::LessAnd),
+    (">&", Operator::GreatAnd),
+    ("<>", Operator::LessGreat),
+    (">|", Operator::Clobber),
+    ("&>", Operator::AndGreat),
+    ("|", Operator::Pipe),
+    ("&", Operator::Amp),
+    (";", Operator::Semi),
+    ("(", Operator::LParen),
+    (")", Operator::RParen),
+    ("<", Operator::Less),
+    (">", Operator::Great),
+];
+
+impl Operator {
+    pub fn is_redirect(&self) -> bool {
+        matches!(
+            self,
+            Operator::Less
+                | Operator::Great
+                | Operator::DGreat
+                | Operator::LessAnd
+                | Operator::GreatAnd
+                | Operator::LessGreat
+                | Operator::Clobber
+                | Operator::AndGreat
+                | Operator::AndDGreat
+                | Operator::DLess
+                | Operator::DLessDash
+                | Operator::TLess
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Word(Word),
+    /// File descriptor number directly in front of a redirection operator
+    IoNumber(u32),
+    Operator(Operator),
+    /// `(( expression ))`
+    Arithmetic(Word),
+    Newline,
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Characters that end an unquoted word
+pub fn is_metachar(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '|' | '&' | ';' | '(' | ')' | '<' | '>')
+}
+
+/// Splits shell input into words and operators on demand
+pub struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+    /// End of the here-document bodies read so far for the current line; the next
+    /// newline token skips over them
+    heredoc_end: Option<usize>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer { input, pos: 0, heredoc_end: None }
+    }
+
+    pub fn input(&self) -> &'a str {
+        self.input
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Skips blanks, escaped newlines and comments
+    fn skip_blanks(&mut self) {
+        loop {
+            let rest = self.rest();
+            if rest.starts_with(' ') || rest.starts_with('\t') {
+                self.pos += 1;
+            } else if rest.starts_with("\\\n") && rest.len() > 2 {
+                // A trailing escaped newline is left for `read_parts` to report
+                self.pos += 2;
+            } else if rest.starts_with('#') {
+                let len = rest.find('\n').unwrap_or(rest.len());
+                self.pos += len;
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, ParseError> {
+        self.skip_blanks();
+        let start = self.pos;
+
+        let kind = match self.peek_char() {
+            None => TokenKind::Eof,
+            Some('\n') => {
+                self.bump();
+                if let Some(end) = self.heredoc_end.take() {
+                    self.pos = end;
+                }
+                TokenKind::Newline
+            }
+            Some(_) => {
+                if let Some(expr) = self.read_arithmetic()? {
+                    TokenKind::Arithmetic(expr)
+                } else if let Some(op) = self.read_operator() {
+                    TokenKind::Operator(op)
+                } else if let Some(fd) = self.read_io_number() {
+                    TokenKind::IoNumber(fd)
+                } else {
+                    TokenKind::Word(self.read_word()?)
+                }
+            }
+        };
+
+        Ok(Token { kind, span: Span::new(start, self.pos) })
+    }
+
+    fn read_operator(&mut self) -> Option<Operator> {
+        let rest = self.rest();
+        let (text, op) = OPERATORS.iter().find(|(text, _)| rest.starts_with(text))?;
+        self.pos += text.len();
+        Some(*op)
+    }
+
+    /// Reads a run of digits immediately followed by `<` or `>`
+    fn read_io_number(&mut self) -> Option<u32> {
+        let rest = self.rest();
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 || !matches!(rest[digits..].chars().next(), Some('<') | Some('>')) {
+            return None;
+        }
+        let fd = rest[..digits].parse().ok()?;
+        self.pos += digits;
+        Some(fd)
+    }
+
+    fn read_word(&mut self) -> Result<Word, ParseError> {
+        let start = self.pos;
+        let parts = self.read_parts(is_metachar)?;
+        Ok(Word { parts, span: Span::new(start, self.pos) })
+    }
+
+    /// Reads the body of a here-document whose redirection was just read. The body
+    /// starts on the line after the current one (or after the previous here-document
+    /// on this line) and runs up to a line consisting of the delimiter. With
+    /// `strip_tabs` (`<<-`) leading tabs are removed from every line
+    pub fn read_heredoc(&mut self, delimiter: &Word, strip_tabs: bool) -> Result<Word, ParseError> {
+        let mut quoted = false;
+        let mut delim = String::new();
+        delimiter_text(&delimiter.parts, &mut delim, &mut quoted);
+        let unterminated = || ParseError::UnterminatedHereDoc(delim.clone());
+
+        let start = match self.heredoc_end {
+            Some(end) => end,
+            None => self.pos + self.rest().find('\n').ok_or_else(unterminated)? + 1,
+        };
+        let mut body = String::new();
+        let mut line_start = start;
+        loop {
+            if line_start >= self.input.len() {
+                return Err(unterminated());
+            }
+            let rest = &self.input[line_start..];
+            let len = rest.find('\n').unwrap_or(rest.len());
+            let line = &rest[..len];
+            let line = if strip_tabs { line.trim_start_matches('\t') } else { line };
+            line_start += (len + 1).min(rest.len());
+            if line == delim {
+                break;
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        self.heredoc_end = Some(line_start);
+
+        let span = Span::new(start, line_start);
+        if quoted {
+            return Ok(Word { parts: vec![WordPart::Quoted(body)], span });
+        }
+        let parts = Lexer::new(&body).read_expanding_text()?;
+        Ok(Word { parts: vec![WordPart::DoubleQuoted(parts)], span })
+    }
+
+    /// Reads text in which only `$` expansions, backquotes and backslashes before `$`,
+    /// `` ` ``, `\` and newline are special: unquoted here-document bodies and
+    /// arithmetic expressions
+    fn read_expanding_text(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+        while let Some(c) = self.bump() {
+            match c {
+                '\\' => match self.peek_char() {
+                    Some('\n') => {
+                        self.bump();
+                    }
+                    Some(escaped @ ('$' | '`' | '\\')) => {
+                        self.bump();
+                        push_literal(&mut parts, escaped);
+                    }
+                    _ => push_literal(&mut parts, '\\'),
+                },
+                '$' => match self.read_dollar()? {
+                    Some(part) => parts.push(part),
+                    None => push_literal(&mut parts, '$'),
+                },
+                '`' => parts.push(self.read_backquoted()?),
+                _ => push_literal(&mut parts, c),
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Reads a legacy `` `...` `` substitution; the opening backquote is already
+    /// consumed. A backslash only escapes `$`, `` ` `` and `\` inside it
+    fn read_backquoted(&mut self) -> Result<WordPart, ParseError> {
+        let mut command = String::new();
+        loop {
+            match self.bump() {
+                Some('`') => break,
+                Some('\\') => match self.bump() {
+                    Some(c @ ('$' | '`' | '\\')) => command.push(c),
+                    Some(c) => {
+                        command.push('\\');
+                        command.push(c);
+                    }
+                    None => return Err(ParseError::UnterminatedQuote('`')),
+                },
+                Some(c) => command.push(c),
+                None => return Err(ParseError::UnterminatedQuote('`')),
+            }
+        }
+        Ok(WordPart::CommandSubst(parser::parse(&command)?))
+    }
+
+    /// Reads word parts up to (but not including) the first unquoted character matching `stop`
+    fn read_parts(&mut self, stop: fn(char) -> bool) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+
+        while let Some(c) = self.peek_char() {
+            if stop(c) {
+                break;
+            }
+            self.bump();
+            match c {
+                '\\' => match self.bump() {
+                    // The line continues on input that has not been read yet
+                    Some('\n') if self.pos == self.input.len() => return Err(ParseError::UnexpectedEof),
+                    Some('\n') => {}
+                    Some(escaped) => push_quoted(&mut parts, escaped),
+                    None => return Err(ParseError::UnexpectedEof),
+                },
+                '\'' => {
+                    let text = self.read_single_quoted()?;
+                    push_quoted_str(&mut parts, &text);
+                }
+                '"' => parts.push(WordPart::DoubleQuoted(self.read_double_quoted()?)),
+                '$' if self.peek_char() == Some('\'') => {
+                    self.bump();
+                    let text = self.read_ansi_c_quoted()?;
+                    push_quoted_str(&mut parts, &text);
+                }
+                '$' if self.peek_char() == Some('"') => {
+                    self.bump();
+                    parts.push(WordPart::DoubleQuoted(self.read_double_quoted()?));
+                }
+                '$' => match self.read_dollar()? {
+                    Some(part) => parts.push(part),
+                    None => push_literal(&mut parts, '$'),
+                },
+                '`' => parts.push(self.read_backquoted()?),
+                _ => push_literal(&mut parts, c),
+            }
+        }
+
+        Ok(parts)
+    }
+
+    /// Reads `((expression))` if the input continues with one. Text starting with `((`
+    /// that is not closed by `))` is left to be read as nested parentheses
+    fn read_arithmetic(&mut self) -> Result<Option<Word>, ParseError> {
+        let Some(body) = self.rest().strip_prefix("((") else {
+            return Ok(None);
+        };
+        let mut depth = 0;
+        let mut end = None;
+        for (i, c) in body.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' => {
+                    if body[i + 1..].starts_with(')') {
+                        end = Some(i);
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let Some(len) = end else {
+            return Ok(None);
+        };
+
+        let start = self.pos + 2;
+        let parts = Lexer::new(&body[..len]).read_expanding_text()?;
+        self.pos = start + len + 2;
+        Ok(Some(Word { parts, span: Span::new(start, start + len) }))
+    }
+
+    /// Reads the expansion following a `$`. Returns `None` when the `$` is just a literal
+    fn read_dollar(&mut self) -> Result<Option<WordPart>, ParseError> {
+        if self.peek_char() == Some('{') {
+            self.bump();
+            return self.read_braced_param().map(|param| Some(WordPart::Param(param)));
+        }
+        if let Some(expr) = self.read_arithmetic()? {
+            return Ok(Some(WordPart::Arithmetic(expr.parts)));
+        }
+        if self.peek_char() == Some('(') {
+            self.bump();
+            let (list, len) = parser::parse_substitution(self.rest())?;
+            self.pos += len;
+            return Ok(Some(WordPart::CommandSubst(list)));
+        }
+        let name = match self.peek_char() {
+            // Outside braces only a single digit names a positional parameter
+            Some(c) if is_special_param(c) || c.is_ascii_digit() => {
+                self.bump();
+                Some(c.to_string())
+            }
+            _ => self.read_name(),
+        };
+        Ok(name.map(|name| WordPart::Param(ParamExpansion { name, index: None, op: ParamOp::Value, braced: false })))
+    }
+
+    /// Reads the parameter named inside `${...}`: a variable name, a positional
+    /// parameter number or a special parameter
+    fn read_param_name(&mut self) -> Option<String> {
+        let rest = self.rest();
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 {
+            self.pos += digits;
+            return Some(rest[..digits].to_string());
+        }
+        match rest.chars().next() {
+            Some(c) if is_special_param(c) => {
+                self.bump();
+                Some(c.to_string())
+            }
+            _ => self.read_name(),
+        }
+    }
+
+    /// Reads a variable name, if one starts at the current position
+    fn read_name(&mut self) -> Option<String> {
+        let rest = self.rest();
+        let len = rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_alphanumeric() || c == '_') || (i == 0 && c.is_ascii_digit()))
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return None;
+        }
+        self.pos += len;
+        Some(rest[..len].to_string())
+    }
+
+    /// Reads the inside of `${...}`; the opening `${` is already consumed
+    fn read_braced_param(&mut self) -> Result<ParamExpansion, ParseError> {
+        let start = self.pos;
+        // `${#}` is the special parameter `#`, while `${#NAME}` is a length
+        let length = self.rest().starts_with('#')
+            && self.rest()[1..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_' || is_special_param(c));
+        // `${!}` is the special parameter `!`, while `${!NAME[@]}` lists indices
+        let keys = self.rest().starts_with('!')
+            && self.rest()[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        if length || keys {
+            self.bump();
+        }
+
+        let Some(name) = self.read_param_name() else {
+            return Err(self.bad_substitution(start));
+        };
+        let index = if is_valid_name(&name) && self.peek_char() == Some('[') {
+            Some(self.read_subscript(start)?)
+        } else {
+            None
+        };
+        if length {
+            self.expect_close_brace(start)?;
+            return Ok(ParamExpansion { name, index, op: ParamOp::Length, braced: true });
+        }
+        if keys {
+            if !matches!(index.as_ref().and_then(Word::as_literal), Some("@" | "*")) {
+                return Err(self.bad_substitution(start));
+            }
+            self.expect_close_brace(start)?;
+            return Ok(ParamExpansion { name, index, op: ParamOp::Keys, braced: true });
+        }
+
+        let rest = self.rest();
+        let colon = rest.starts_with(':');
+        let after_colon = if colon { &rest[1..] } else { rest };
+        let op = match after_colon.chars().next() {
+            Some('}') if !colon => {
+                self.bump();
+                return Ok(ParamExpansion { name, index, op: ParamOp::Value, braced: true });
+            }
+            Some(c @ ('-' | '=' | '?' | '+')) => {
+                self.pos += usize::from(colon) + 1;
+                let word = self.read_brace_word(is_close_brace)?;
+                match c {
+                    '-' => ParamOp::Default { colon, word },
+                    '=' => ParamOp::Assign { colon, word },
+                    '?' => ParamOp::Error { colon, word },
+                    _ => ParamOp::Alternative { colon, word },
+                }
+            }
+            Some(c @ ('#' | '%')) if !colon => {
+                self.bump();
+                let longest = self.peek_char() == Some(c);
+                if longest {
+                    self.bump();
+                }
+                let pattern = self.read_brace_word(is_close_brace)?;
+                if c == '#' {
+                    ParamOp::RemovePrefix { longest, pattern }
+                } else {
+                    ParamOp::RemoveSuffix { longest, pattern }
+                }
+            }
+            Some('/') if !colon => {
+                self.bump();
+                let mode = match self.peek_char() {
+                    Some('/') => ReplaceMode::All,
+                    Some('#') => ReplaceMode::Prefix,
+                    Some('%') => ReplaceMode::Suffix,
+                    _ => ReplaceMode::First,
+                };
+                if mode != ReplaceMode::First {
+                    self.bump();
+                }
+                let pattern = self.read_brace_word(|c| c == '/' || c == '}')?;
+                let replacement = if self.peek_char() == Some('/') {
+                    self.bump();
+                    self.read_brace_word(is_close_brace)?
+                } else {
+                    Word { parts: Vec::new(), span: Span::new(self.pos, self.pos) }
+                };
+                ParamOp::Replace { mode, pattern, replacement }
+            }
+            Some(c) if colon && c != '}' => {
+                self.bump();
+                let offset = self.read_brace_word(|c| c == ':' || c == '}')?;
+                let length = if self.peek_char() == Some(':') {
+                    self.bump();
+                    Some(self.read_brace_word(is_close_brace)?)
+                } else {
+                    None
+                };
+                ParamOp::Slice { offset, length }
+            }
+            None => return Err(ParseError::UnterminatedQuote('}')),
+            Some(_) => return Err(self.bad_substitution(start)),
+        };
+
+        self.expect_close_brace(start)?;
+        Ok(ParamExpansion { name, index, op, braced: true })
+    }
+
+    /// Reads the `[index]` following a name inside `${...}`
+    fn read_subscript(&mut self, start: usize) -> Result<Word, ParseError> {
+        self.bump();
+        let rest = self.rest();
+        match rest.find([']', '}']) {
+            Some(len) if rest[len..].starts_with(']') && len > 0 => {
+                let parts = Lexer::new(&rest[..len]).read_parts(|_| false)?;
+                let span = Span::new(self.pos, self.pos + len);
+                self.pos += len + 1;
+                Ok(Word { parts, span })
+            }
+            _ => Err(self.bad_substitution(start)),
+        }
+    }
+
+    /// Reads the word operand of a `${...}` operator
+    fn read_brace_word(&mut self, stop: fn(char) -> bool) -> Result<Word, ParseError> {
+        let start = self.pos;
+        let parts = self.read_parts(stop)?;
+        Ok(Word { parts, span: Span::new(start, self.pos) })
+    }
+
+    fn expect_close_brace(&mut self, start: usize) -> Result<(), ParseError> {
+        match self.bump() {
+            Some('}') => Ok(()),
+            None => Err(ParseError::UnterminatedQuote('}')),
+            Some(_) => Err(self.bad_substitution(start)),
+        }
+    }
+
+    /// Builds a "bad substitution" error quoting the `${...}` text starting at `start`
+    fn bad_substitution(&self, start: usize) -> ParseError {
+        let rest = &self.input[start..];
+        let end = rest.find('}').map(|i| i + 1).unwrap_or(rest.len());
+        ParseError::BadSubstitution(format!("${{{}", &rest[..end]))
+    }
+
+    /// Reads the body of a `'...'` string, in which no character is special;
+    /// the opening quote is already consumed
+    fn read_single_quoted(&mut self) -> Result<String, ParseError> {
+        let rest = self.rest();
+        let len = rest.find('\'').ok_or(ParseError::UnterminatedQuote('\''))?;
+        self.pos += len + 1;
+        Ok(rest[..len].to_string())
+    }
+
+    /// Reads the body of a `"..."` string; the opening quote is already consumed.
+    /// A backslash only escapes `$`, `` ` ``, `"`, `\` and newline
+    fn read_double_quoted(&mut self) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+        loop {
+            match self.bump() {
+                Some('"') => break,
+                Some('\\') => match self.bump() {
+                    Some('\n') => {}
+                    Some(c @ ('$' | '`' | '"' | '\\')) => push_literal(&mut parts, c),
+                    Some(c) => {
+                        push_literal(&mut parts, '\\');
+                        push_literal(&mut parts, c);
+                    }
+                    None => return Err(ParseError::UnterminatedQuote('"')),
+                },
+                Some('$') => match self.read_dollar()? {
+                    Some(part) => parts.push(part),
+                    None => push_literal(&mut parts, '$'),
+                },
+                Some('`') => parts.push(self.read_backquoted()?),
+                Some(c) => push_literal(&mut parts, c),
+                None => return Err(ParseError::UnterminatedQuote('"')),
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Reads the body of a bash `$'...'` string, decoding its backslash escapes;
+    /// the opening `$'` is already consumed
+    fn read_ansi_c_quoted(&mut self) -> Result<String, ParseError> {
+        let mut text = String::new();
+        loop {
+            let c = self.bump().ok_or(ParseError::UnterminatedQuote('\''))?;
+            match c {
+                '\'' => break,
+                '\\' => {
+                    let escaped = self.bump().ok_or(ParseError::UnterminatedQuote('\''))?;
+                    match escaped {
+                        'a' => text.push('\x07'),
+                        'b' => text.push('\x08'),
+                        'e' | 'E' => text.push('\x1b'),
+                        'f' => text.push('\x0c'),
+                        'n' => text.push('\n'),
+                        'r' => text.push('\r'),
+                        't' => text.push('\t'),
+                        'v' => text.push('\x0b'),
+                        '\\' | '\'' | '"' | '?' => text.push(escaped),
+                        '0'..='7' => {
+                            let digits = self.take_digits(2, 8);
+                            let value = u32::from_str_radix(&format!("{}{}", escaped, digits), 8).unwrap_or(0);
+                            text.extend(char::from_u32(value & 0xff));
+                        }
+                        'x' | 'u' | 'U' => {
+                            let max = match escaped {
+                                'x' => 2,
+                                'u' => 4,
+                                _ => 8,
+                            };
+                            let digits = self.take_digits(max, 16);
+                            if digits.is_empty() {
+                                text.push('\\');
+                                text.push(escaped);
+                            } else {
+                                let value = u32::from_str_radix(&digits, 16).unwrap_or(0);
+                                text.extend(char::from_u32(value));
+                            }
+                        }
+                        'c' => {
+                            let control = self.bump().ok_or(ParseError::UnterminatedQuote('\''))?;
+                            text.extend(char::from_u32(control.to_ascii_uppercase() as u32 & 0x1f));
+                        }
+                        other => {
+                            text.push('\\');
+                            text.push(other);
+                        }
+                    }
+                }
+                _ => text.push(c),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Consumes up to `max` digits in the given radix
+    fn take_digits(&mut self, max: usize, radix: u32) -> String {
+        let mut digits = String::new();
+        while digits.len() < max {
+            match self.peek_char() {
+                Some(c) if c.is_digit(radix) => {
+                    digits.push(c);
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+        digits
+    }
+}
+
+/// Characters naming special parameters such as `$?` and `$@`
+fn is_special_param(c: char) -> bool {
+    matches!(c, '?' | '$' | '!' | '#' | '@' | '*' | '-')
+}
+
+fn is_close_brace(c: char) -> bool {
+    c == '}'
+}
+
+fn push_literal(parts: &mut Vec<WordPart>, c: char) {
+    match parts.last_mut() {
+        Some(WordPart::Literal(text)) => text.push(c),
+        _ => parts.push(WordPart::Literal(c.to_string())),
+    }
+}
+
+fn push_quoted(parts: &mut Vec<WordPart>, c: char) {
+    push_quoted_str(parts, c.encode_utf8(&mut [0; 4]));
+}
+
+fn push_quoted_str(parts: &mut Vec<WordPart>, s: &str) {
+    match parts.last_mut() {
+        Some(WordPart::Quoted(text)) => text.push_str(s),
+        _ => parts.push(WordPart::Quoted(s.to_string())),
+    }
+}
+
+/// Collects the text of a here-document delimiter with quotes removed, noting
+/// whether any part of it was quoted
+fn delimiter_text(parts: &[WordPart], text: &mut String, quoted: &mut bool) {
+    for part in parts {
+        match part {
+            WordPart::Literal(literal) => text.push_str(literal),
+            WordPart::Quoted(literal) => {
+                text.push_str(literal);
+                *quoted = true;
+            }
+            WordPart::DoubleQuoted(inner) => {
+                delimiter_text(inner, text, quoted);
+                *quoted = true;
+            }
+            WordPart::Param(param) => {
+                text.push('$');
+                text.push_str(&param.name);
+            }
+            // Substitutions are not run in delimiters; they have no sensible text
+            WordPart::CommandSubst(_) | WordPart::Arithmetic(_) | WordPart::Array(_) => {}
+        }
+    }
+}
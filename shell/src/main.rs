@@ -1,18 +1,42 @@
+mod arithmetic;
+mod ast;
+mod brace;
+mod lexer;
+mod parser;
+mod redirection;
 mod shell;
 mod shell_type;
 mod utils;
 mod builtin;
 mod command_execution;
+mod conditional;
+mod control_flow;
+mod error;
+mod expansion;
+mod jobs;
+mod options;
+mod signals;
+mod traps;
+mod variables;
 
 use shell::Shell;
 // use shell_type::ShellType;
 use utils::select_shell_type;
 use std::io;
+use std::process;
+use std::thread;
 
 fn main() -> io::Result<()> {
     println!("Starting shell application...");
     let shell_type = select_shell_type()?;
     let mut shell = Shell::new(shell_type)?;
     println!("Shell created successfully, entering main loop...");
-    shell.run()
+    // Function calls recurse on the Rust stack, so the shell gets the same large stack
+    // as the threads it starts
+    let status = thread::Builder::new()
+        .stack_size(command_execution::THREAD_STACK_SIZE)
+        .spawn(move || shell.run())?
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("the shell panicked")))?;
+    process::exit(status)
 }
@@ -0,0 +1,84 @@
+/// Options changed with `set -o name` / `set +o name` and `shopt -s name` / `shopt -u name`
+#[derive(Debug, Clone, Default)]
+pub struct ShellOptions {
+    /// `set -e`: the shell exits when a command fails outside a condition
+    pub errexit: bool,
+    /// `set -C`: `>` refuses to overwrite an existing file, while `>|` still does
+    pub noclobber: bool,
+    /// `set -f`: no pathname expansion
+    pub noglob: bool,
+    /// `set -u`: expanding an unset variable is an error
+    pub nounset: bool,
+    /// `set -v`: input lines are echoed to standard error as they are read
+    pub verbose: bool,
+    /// `set -x`: commands are printed to standard error after expansion, after `PS4`
+    pub xtrace: bool,
+    /// A pipeline's status is that of the last stage to fail, not of the last stage
+    pub pipefail: bool,
+    /// Patterns may match names starting with `.`
+    pub dotglob: bool,
+    /// A pattern without matches is an error
+    pub failglob: bool,
+    /// `**` matches any number of directories
+    pub globstar: bool,
+    /// A pattern without matches expands to nothing
+    pub nullglob: bool,
+}
+
+impl ShellOptions {
+    /// Names accepted by `set -o`, in the order `set -o` lists them
+    pub const SET_NAMES: &'static [&'static str] =
+        &["errexit", "noclobber", "noglob", "nounset", "pipefail", "verbose", "xtrace"];
+
+    /// Single-letter `set` options and the names they stand for
+    pub const LETTERS: &'static [(char, &'static str)] =
+        &[('e', "errexit"), ('C', "noclobber"), ('f', "noglob"), ('u', "nounset"), ('v', "verbose"), ('x', "xtrace")];
+
+    /// Names accepted by `shopt`, in the order `shopt` lists them
+    pub const SHOPT_NAMES: &'static [&'static str] = &["dotglob", "failglob", "globstar", "nullglob"];
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        Some(match name {
+            "errexit" => &mut self.errexit,
+            "noclobber" => &mut self.noclobber,
+            "noglob" => &mut self.noglob,
+            "nounset" => &mut self.nounset,
+            "verbose" => &mut self.verbose,
+            "xtrace" => &mut self.xtrace,
+            "pipefail" => &mut self.pipefail,
+            "dotglob" => &mut self.dotglob,
+            "failglob" => &mut self.failglob,
+            "globstar" => &mut self.globstar,
+            "nullglob" => &mut self.nullglob,
+            _ => return None,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        Some(match name {
+            "errexit" => self.errexit,
+            "noclobber" => self.noclobber,
+            "noglob" => self.noglob,
+            "nounset" => self.nounset,
+            "verbose" => self.verbose,
+            "xtrace" => self.xtrace,
+            "pipefail" => self.pipefail,
+            "dotglob" => self.dotglob,
+            "failglob" => self.failglob,
+            "globstar" => self.globstar,
+            "nullglob" => self.nullglob,
+            _ => return None,
+        })
+    }
+
+    /// The letters of the single-letter options that are on, as `$-` shows them
+    pub fn letters(&self) -> String {
+        Self::LETTERS.iter().filter(|(_, name)| self.get(name) == Some(true)).map(|&(letter, _)| letter).collect()
+    }
+
+    /// Turns an option on or off, returning `None` for an unknown name
+    pub fn set(&mut self, name: &str, value: bool) -> Option<()> {
+        *self.flag_mut(name)? = value;
+        Some(())
+    }
+}
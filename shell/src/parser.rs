@@ -0,0 +1,990 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::ast::*;
+use crate::conditional;
+use crate::lexer::{Lexer, Operator, Token, TokenKind};
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ParseError {
+    #[error("syntax error near unexpected token `{token}'")]
+    UnexpectedToken { token: String, span: Span },
+    #[error("syntax error: unexpected end of file")]
+    UnexpectedEof,
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    UnterminatedQuote(char),
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+    #[error("here-document delimited by end-of-file (wanted `{0}')")]
+    UnterminatedHereDoc(String),
+}
+
+impl ParseError {
+    /// True if the input ended before the command did, so reading more lines may
+    /// complete it
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnexpectedEof | ParseError::UnterminatedQuote(_) | ParseError::UnterminatedHereDoc(_)
+        )
+    }
+}
+
+/// Parses a complete piece of shell input into a command list
+pub fn parse(input: &str) -> Result<List, ParseError> {
+    Parser::new(input).parse_program()
+}
+
+/// Parses the body of a `$(...)` substitution at the start of `input`, up to and
+/// including the closing `)`. Returns the list and the number of bytes consumed
+pub fn parse_substitution(input: &str) -> Result<(List, usize), ParseError> {
+    let mut parser = Parser::new(input);
+    let list = parser.parse_list()?;
+    let close = parser.expect_operator(Operator::RParen)?;
+    Ok((list, close.end))
+}
+
+/// Splits `input` into words, as the elements of an array given to `declare` as text
+pub fn parse_words(input: &str) -> Result<Vec<Word>, ParseError> {
+    let mut parser = Parser::new(input);
+    let mut words = Vec::new();
+    loop {
+        let token = parser.next()?;
+        match token.kind {
+            TokenKind::Word(word) => words.push(word),
+            TokenKind::Newline => {}
+            TokenKind::Eof => return Ok(words),
+            _ => return Err(parser.unexpected(&token)),
+        }
+    }
+}
+
+/// True if `input` stops in the middle of a command, such as inside quotes or a
+/// here-document, so that the next line has to be read before running it
+pub fn needs_more_input(input: &str) -> bool {
+    matches!(parse(input), Err(e) if e.is_incomplete())
+}
+
+/// Recursive-descent parser over the tokens produced by [`Lexer`]
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<Token>,
+    /// End of the last token taken with `next`
+    last_end: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Parser<'a> {
+        Parser { lexer: Lexer::new(input), peeked: None, last_end: 0 }
+    }
+
+    fn peek(&mut self) -> Result<&Token, ParseError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lexer.next_token()?);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    fn next(&mut self) -> Result<Token, ParseError> {
+        let token = match self.peeked.take() {
+            Some(token) => token,
+            None => self.lexer.next_token()?,
+        };
+        self.last_end = token.span.end;
+        Ok(token)
+    }
+
+    fn peek_operator(&mut self) -> Result<Option<Operator>, ParseError> {
+        Ok(match self.peek()?.kind {
+            TokenKind::Operator(op) => Some(op),
+            _ => None,
+        })
+    }
+
+    /// True if the next token is the unquoted word `word`
+    fn peek_reserved(&mut self, word: &str) -> Result<bool, ParseError> {
+        Ok(matches!(&self.peek()?.kind, TokenKind::Word(w) if w.as_literal() == Some(word)))
+    }
+
+    fn unexpected(&self, token: &Token) -> ParseError {
+        match token.kind {
+            TokenKind::Eof => ParseError::UnexpectedEof,
+            TokenKind::Newline => ParseError::UnexpectedToken {
+                token: "newline".to_string(),
+                span: token.span,
+            },
+            _ => ParseError::UnexpectedToken {
+                token: self.lexer.input()[token.span.start..token.span.end].to_string(),
+                span: token.span,
+            },
+        }
+    }
+
+    fn expect_reserved(&mut self, word: &str) -> Result<Span, ParseError> {
+        if self.peek_reserved(word)? {
+            return Ok(self.next()?.span);
+        }
+        let token = self.next()?;
+        Err(self.unexpected(&token))
+    }
+
+    fn expect_operator(&mut self, expected: Operator) -> Result<Span, ParseError> {
+        let token = self.next()?;
+        match token.kind {
+            TokenKind::Operator(op) if op == expected => Ok(token.span),
+            _ => Err(self.unexpected(&token)),
+        }
+    }
+
+    fn skip_newlines(&mut self) -> Result<(), ParseError> {
+        while self.peek()?.kind == TokenKind::Newline {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    pub fn parse_program(&mut self) -> Result<List, ParseError> {
+        let list = self.parse_list()?;
+        let token = self.next()?;
+        if token.kind != TokenKind::Eof {
+            return Err(self.unexpected(&token));
+        }
+        Ok(list)
+    }
+
+    /// True when the next token closes the list currently being parsed
+    fn at_list_end(&mut self) -> Result<bool, ParseError> {
+        if let TokenKind::Word(word) = &self.peek()?.kind {
+            return Ok(word.as_literal().is_some_and(|word| CLOSING_WORDS.contains(&word)));
+        }
+        Ok(matches!(
+            self.peek()?.kind,
+            TokenKind::Eof
+                | TokenKind::Operator(Operator::RParen | Operator::DSemi | Operator::SemiAnd | Operator::DSemiAnd)
+        ))
+    }
+
+    fn parse_list(&mut self) -> Result<List, ParseError> {
+        let mut items = Vec::new();
+        let start = self.peek()?.span;
+        let mut span = Span::new(start.start, start.start);
+
+        loop {
+            self.skip_newlines()?;
+            if self.at_list_end()? {
+                break;
+            }
+
+            let and_or = self.parse_and_or()?;
+            let mut item_span = and_or.first.span;
+            if let Some((_, last)) = and_or.rest.last() {
+                item_span = item_span.to(last.span);
+            }
+            span = span.to(item_span);
+            let text = self.lexer.input()[item_span.start..item_span.end].trim().to_string();
+
+            let background = match self.peek_operator()? {
+                Some(Operator::Semi) => {
+                    self.next()?;
+                    false
+                }
+                Some(Operator::Amp) => {
+                    self.next()?;
+                    true
+                }
+                _ => {
+                    let separated = self.peek()?.kind == TokenKind::Newline;
+                    items.push(ListItem { and_or, background: false, text });
+                    if separated {
+                        continue;
+                    }
+                    break;
+                }
+            };
+            items.push(ListItem { and_or, background, text });
+        }
+
+        Ok(List { items, span })
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+
+        loop {
+            let op = match self.peek_operator()? {
+                Some(Operator::AndIf) => AndOrOp::And,
+                Some(Operator::OrIf) => AndOrOp::Or,
+                _ => break,
+            };
+            self.next()?;
+            self.skip_newlines()?;
+            rest.push((op, self.parse_pipeline()?));
+        }
+
+        Ok(AndOr { first, rest })
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let start = self.peek()?.span;
+        let negated = self.peek_reserved("!")?;
+        if negated {
+            self.next()?;
+        }
+        let mut commands = vec![self.parse_command()?];
+        let mut end = self.peek()?.span.start;
+
+        while self.peek_operator()? == Some(Operator::Pipe) {
+            self.next()?;
+            self.skip_newlines()?;
+            commands.push(self.parse_command()?);
+            end = self.peek()?.span.start;
+        }
+
+        let text = self.lexer.input()[start.start..end].trim().to_string();
+        Ok(Pipeline { negated, commands, span: Span::new(start.start, end), text })
+    }
+
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        if self.peek_reserved("function")? {
+            let start = self.next()?.span.start;
+            let token = self.next()?;
+            let name = match &token.kind {
+                TokenKind::Word(word) => word.as_literal().map(str::to_string),
+                _ => None,
+            };
+            let Some(name) = name else {
+                return Err(self.unexpected(&token));
+            };
+            if self.peek_operator()? == Some(Operator::LParen) {
+                self.next()?;
+                self.expect_operator(Operator::RParen)?;
+            }
+            return self.parse_function_body(name, start);
+        }
+
+        let compound = if self.peek_reserved("if")? {
+            self.parse_if()?
+        } else if self.peek_reserved("while")? || self.peek_reserved("until")? {
+            self.parse_while()?
+        } else if self.peek_reserved("for")? {
+            self.parse_for()?
+        } else if self.peek_reserved("case")? {
+            self.parse_case()?
+        } else if self.peek_reserved("[[")? {
+            self.parse_conditional()?
+        } else if let TokenKind::Arithmetic(_) = self.peek()?.kind {
+            let TokenKind::Arithmetic(expr) = self.next()?.kind else {
+                unreachable!()
+            };
+            CompoundCommand::Arithmetic(expr)
+        } else if self.peek_reserved("{")? {
+            self.next()?;
+            let body = self.parse_compound_list()?;
+            self.expect_reserved("}")?;
+            CompoundCommand::BraceGroup(body)
+        } else if self.peek_operator()? == Some(Operator::LParen) {
+            self.next()?;
+            let body = self.parse_compound_list()?;
+            self.expect_operator(Operator::RParen)?;
+            CompoundCommand::Subshell(body)
+        } else {
+            let command = self.parse_simple_command()?;
+            // `name ( )` starts a function definition
+            if self.peek_operator()? == Some(Operator::LParen) {
+                if let Some(name) = function_name(&command) {
+                    self.next()?;
+                    self.expect_operator(Operator::RParen)?;
+                    return self.parse_function_body(name.to_string(), command.span.start);
+                }
+            }
+            return Ok(Command::Simple(command));
+        };
+
+        let mut redirects = Vec::new();
+        while self.at_redirect()? {
+            redirects.push(self.parse_redirect()?);
+        }
+        Ok(Command::Compound(compound, redirects))
+    }
+
+    /// Parses the compound command that makes up the body of a function whose
+    /// definition began at byte `start`
+    fn parse_function_body(&mut self, name: String, start: usize) -> Result<Command, ParseError> {
+        self.skip_newlines()?;
+        if !self.at_compound_command()? {
+            let token = self.next()?;
+            return Err(self.unexpected(&token));
+        }
+        let Command::Compound(body, redirects) = self.parse_command()? else {
+            unreachable!("a compound command was expected");
+        };
+        let source = self.lexer.input()[start..self.last_end].to_string();
+        Ok(Command::Function(Arc::new(FunctionDef { name, body, redirects, source })))
+    }
+
+    /// True if the next token starts a compound command
+    fn at_compound_command(&mut self) -> Result<bool, ParseError> {
+        if let TokenKind::Word(word) = &self.peek()?.kind {
+            return Ok(word
+                .as_literal()
+                .is_some_and(|word| ["if", "while", "until", "for", "case", "[[", "{"].contains(&word)));
+        }
+        Ok(matches!(
+            self.peek()?.kind,
+            TokenKind::Arithmetic(_) | TokenKind::Operator(Operator::LParen)
+        ))
+    }
+
+    /// Parses the body of a compound command, which must contain at least one command
+    fn parse_compound_list(&mut self) -> Result<List, ParseError> {
+        let list = self.parse_list()?;
+        if list.items.is_empty() {
+            let token = self.next()?;
+            return Err(self.unexpected(&token));
+        }
+        Ok(list)
+    }
+
+    fn parse_if(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_reserved("if")?;
+        let mut branches = Vec::new();
+        let mut else_branch = None;
+        loop {
+            let condition = self.parse_compound_list()?;
+            self.expect_reserved("then")?;
+            branches.push((condition, self.parse_compound_list()?));
+
+            if self.peek_reserved("elif")? {
+                self.next()?;
+                continue;
+            }
+            if self.peek_reserved("else")? {
+                self.next()?;
+                else_branch = Some(self.parse_compound_list()?);
+            }
+            self.expect_reserved("fi")?;
+            return Ok(CompoundCommand::If { branches, else_branch });
+        }
+    }
+
+    fn parse_while(&mut self) -> Result<CompoundCommand, ParseError> {
+        let until = self.peek_reserved("until")?;
+        self.next()?;
+        let condition = self.parse_compound_list()?;
+        let body = self.parse_do_group()?;
+        Ok(CompoundCommand::While { condition, body, until })
+    }
+
+    /// Parses `do list done`
+    fn parse_do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_reserved("do")?;
+        let body = self.parse_compound_list()?;
+        self.expect_reserved("done")?;
+        Ok(body)
+    }
+
+    /// Skips a `;` or newlines, as allowed before `do`
+    fn skip_separator(&mut self) -> Result<(), ParseError> {
+        if self.peek_operator()? == Some(Operator::Semi) {
+            self.next()?;
+        }
+        self.skip_newlines()
+    }
+
+    fn parse_for(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_reserved("for")?;
+        let token = self.next()?;
+        let name = match token.kind {
+            TokenKind::Arithmetic(expr) => {
+                let [init, condition, step] = split_arithmetic_for(&expr).ok_or_else(|| ParseError::UnexpectedToken {
+                    token: format!("(({}))", &self.lexer.input()[expr.span.start..expr.span.end]),
+                    span: token.span,
+                })?;
+                self.skip_separator()?;
+                let body = self.parse_do_group()?;
+                return Ok(CompoundCommand::ArithmeticFor { init, condition, step, body });
+            }
+            TokenKind::Word(ref word) => match word.as_literal() {
+                Some(name) if is_valid_name(name) => name.to_string(),
+                _ => return Err(self.unexpected(&token)),
+            },
+            _ => return Err(self.unexpected(&token)),
+        };
+
+        self.skip_newlines()?;
+        let mut words = None;
+        if self.peek_reserved("in")? {
+            self.next()?;
+            let mut list = Vec::new();
+            while let TokenKind::Word(_) = self.peek()?.kind {
+                let TokenKind::Word(word) = self.next()?.kind else {
+                    unreachable!()
+                };
+                list.push(word);
+            }
+            words = Some(list);
+            match self.peek()?.kind {
+                TokenKind::Operator(Operator::Semi) | TokenKind::Newline => {}
+                _ => {
+                    let token = self.next()?;
+                    return Err(self.unexpected(&token));
+                }
+            }
+        }
+        self.skip_separator()?;
+        let body = self.parse_do_group()?;
+        Ok(CompoundCommand::For { name, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_reserved("case")?;
+        let token = self.next()?;
+        let TokenKind::Word(word) = token.kind else {
+            return Err(self.unexpected(&token));
+        };
+        self.skip_newlines()?;
+        self.expect_reserved("in")?;
+        self.skip_newlines()?;
+
+        let mut items = Vec::new();
+        while !self.peek_reserved("esac")? {
+            if self.peek_operator()? == Some(Operator::LParen) {
+                self.next()?;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                let token = self.next()?;
+                let TokenKind::Word(pattern) = token.kind else {
+                    return Err(self.unexpected(&token));
+                };
+                patterns.push(pattern);
+                if self.peek_operator()? != Some(Operator::Pipe) {
+                    break;
+                }
+                self.next()?;
+            }
+            self.expect_operator(Operator::RParen)?;
+
+            let body = self.parse_list()?;
+            let terminator = match self.peek_operator()? {
+                Some(Operator::DSemi) => CaseTerminator::Break,
+                Some(Operator::SemiAnd) => CaseTerminator::FallThrough,
+                Some(Operator::DSemiAnd) => CaseTerminator::Continue,
+                // The last item may omit its terminator
+                _ => {
+                    items.push(CaseItem { patterns, body, terminator: CaseTerminator::Break });
+                    break;
+                }
+            };
+            self.next()?;
+            self.skip_newlines()?;
+            items.push(CaseItem { patterns, body, terminator });
+        }
+        self.expect_reserved("esac")?;
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    fn parse_conditional(&mut self) -> Result<CompoundCommand, ParseError> {
+        self.expect_reserved("[[")?;
+        let expr = self.parse_cond_or()?;
+        self.skip_newlines()?;
+        self.expect_reserved("]]")?;
+        Ok(CompoundCommand::Conditional(expr))
+    }
+
+    fn parse_cond_or(&mut self) -> Result<CondExpr, ParseError> {
+        let mut expr = self.parse_cond_and()?;
+        while self.peek_operator()? == Some(Operator::OrIf) {
+            self.next()?;
+            expr = CondExpr::Or(Box::new(expr), Box::new(self.parse_cond_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_and(&mut self) -> Result<CondExpr, ParseError> {
+        let mut expr = self.parse_cond_not()?;
+        while self.peek_operator()? == Some(Operator::AndIf) {
+            self.next()?;
+            expr = CondExpr::And(Box::new(expr), Box::new(self.parse_cond_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_not(&mut self) -> Result<CondExpr, ParseError> {
+        self.skip_newlines()?;
+        if self.peek_reserved("!")? {
+            self.next()?;
+            return Ok(CondExpr::Not(Box::new(self.parse_cond_not()?)));
+        }
+        if self.peek_operator()? == Some(Operator::LParen) {
+            self.next()?;
+            let expr = self.parse_cond_or()?;
+            self.skip_newlines()?;
+            self.expect_operator(Operator::RParen)?;
+            return Ok(expr);
+        }
+
+        let word = self.cond_word()?;
+        let op = word.as_literal().unwrap_or_default().to_string();
+        if conditional::is_unary_operator(&op) && !self.at_cond_end()? {
+            return Ok(CondExpr::Unary(op, self.cond_word()?));
+        }
+
+        let binary = match &self.peek()?.kind {
+            TokenKind::Word(w) => w
+                .as_literal()
+                .filter(|op| *op == "=~" || conditional::is_binary_operator(op))
+                .map(str::to_string),
+            TokenKind::Operator(Operator::Less) => Some("<".to_string()),
+            TokenKind::Operator(Operator::Great) => Some(">".to_string()),
+            _ => None,
+        };
+        let Some(binary) = binary else {
+            return Ok(CondExpr::Word(word));
+        };
+        self.next()?;
+        let right = if binary == "=~" { self.parse_regex_word()? } else { self.cond_word()? };
+        Ok(CondExpr::Binary(word, binary, right))
+    }
+
+    /// True if the next token ends an operand of `[[`
+    fn at_cond_end(&mut self) -> Result<bool, ParseError> {
+        Ok(self.peek_reserved("]]")?
+            || !matches!(self.peek()?.kind, TokenKind::Word(_))
+            || matches!(&self.peek()?.kind, TokenKind::Word(w) if w.as_literal().is_some_and(is_cond_binary)))
+    }
+
+    /// Takes the next word inside `[[ ]]`, which must not be the closing `]]`
+    fn cond_word(&mut self) -> Result<Word, ParseError> {
+        let token = self.next()?;
+        match token.kind {
+            TokenKind::Word(word) if word.as_literal() != Some("]]") => Ok(word),
+            _ => Err(self.unexpected(&token)),
+        }
+    }
+
+    /// Reads the regular expression after `=~`. Parentheses and `|` belong to the
+    /// expression, and so does any space inside parentheses
+    fn parse_regex_word(&mut self) -> Result<Word, ParseError> {
+        let start = self.peek()?.span;
+        let mut parts = Vec::new();
+        let mut depth = 0;
+        let mut end = start.start;
+        loop {
+            let token = self.peek()?;
+            let adjacent = token.span.start == end;
+            if !parts.is_empty() && !adjacent && depth == 0 {
+                break;
+            }
+            let kind = token.kind.clone();
+            let span = token.span;
+            match kind {
+                TokenKind::Word(word) if depth > 0 || word.as_literal() != Some("]]") => {
+                    if span.start > end {
+                        parts.push(WordPart::Literal(self.lexer.input()[end..span.start].to_string()));
+                    }
+                    parts.extend(word.parts);
+                }
+                TokenKind::Operator(op @ (Operator::LParen | Operator::RParen | Operator::Pipe)) => {
+                    match op {
+                        Operator::LParen => depth += 1,
+                        Operator::RParen if depth == 0 => break,
+                        Operator::RParen => depth -= 1,
+                        _ => {}
+                    }
+                    parts.push(WordPart::Literal(self.lexer.input()[end..span.end].to_string()));
+                }
+                _ => break,
+            }
+            self.next()?;
+            end = span.end;
+        }
+        if parts.is_empty() {
+            let token = self.next()?;
+            return Err(self.unexpected(&token));
+        }
+        Ok(Word { parts, span: Span::new(start.start, end) })
+    }
+
+    fn at_redirect(&mut self) -> Result<bool, ParseError> {
+        Ok(match self.peek()?.kind {
+            TokenKind::IoNumber(_) => true,
+            TokenKind::Operator(op) => op.is_redirect(),
+            _ => false,
+        })
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand {
+            assignments: Vec::new(),
+            words: Vec::new(),
+            redirects: Vec::new(),
+            span: Span::new(self.peek()?.span.start, self.peek()?.span.start),
+        };
+
+        loop {
+            if self.at_redirect()? {
+                let redirect = self.parse_redirect()?;
+                command.span = command.span.to(redirect.span);
+                command.redirects.push(redirect);
+                continue;
+            }
+
+            let mut word = match &self.peek()?.kind {
+                TokenKind::Word(_) => match self.next()?.kind {
+                    TokenKind::Word(word) => word,
+                    _ => unreachable!(),
+                },
+                _ => break,
+            };
+            command.span = command.span.to(word.span);
+
+            let assignment = split_assignment(&word);
+            let array = match &assignment {
+                Some(assignment) if assignment.value.parts.is_empty() => self.parse_array_literal(word.span.end)?,
+                _ => None,
+            };
+            if command.words.is_empty() {
+                if let Some(mut assignment) = assignment {
+                    if let Some(array) = array {
+                        command.span = command.span.to(array.span);
+                        assignment.value = array;
+                    }
+                    command.assignments.push(assignment);
+                    continue;
+                }
+            }
+            // Declaration builtins take arrays too, as in `declare -a NAME=(...)`
+            if let Some(array) = array {
+                if !command.words.first().and_then(Word::as_literal).is_some_and(is_declaration_builtin) {
+                    let span = Span::new(array.span.start, array.span.start + 1);
+                    return Err(self.unexpected(&Token { kind: TokenKind::Operator(Operator::LParen), span }));
+                }
+                command.span = command.span.to(array.span);
+                word.span = word.span.to(array.span);
+                word.parts.extend(array.parts);
+            }
+            command.words.push(word);
+        }
+
+        if command.assignments.is_empty() && command.words.is_empty() && command.redirects.is_empty() {
+            let token = self.next()?;
+            return Err(self.unexpected(&token));
+        }
+        Ok(command)
+    }
+
+    /// Parses the `(word...)` of an array assignment when it follows straight after the
+    /// `=` ending at byte `end`
+    fn parse_array_literal(&mut self, end: usize) -> Result<Option<Word>, ParseError> {
+        let token = self.peek()?;
+        if token.kind != TokenKind::Operator(Operator::LParen) || token.span.start != end {
+            return Ok(None);
+        }
+        let start = self.next()?.span;
+        let mut words = Vec::new();
+        loop {
+            self.skip_newlines()?;
+            let token = self.next()?;
+            match token.kind {
+                TokenKind::Word(word) => words.push(word),
+                TokenKind::Operator(Operator::RParen) => {
+                    let span = start.to(token.span);
+                    return Ok(Some(Word { parts: vec![WordPart::Array(words)], span }));
+                }
+                _ => return Err(self.unexpected(&token)),
+            }
+        }
+    }
+
+    fn parse_redirect(&mut self) -> Result<Redirect, ParseError> {
+        let mut token = self.next()?;
+        let start = token.span;
+        let mut fd = None;
+        if let TokenKind::IoNumber(n) = token.kind {
+            fd = Some(n);
+            token = self.next()?;
+        }
+
+        let kind = match token.kind {
+            TokenKind::Operator(Operator::Less) => RedirectKind::Input,
+            TokenKind::Operator(Operator::Great) => RedirectKind::Output,
+            TokenKind::Operator(Operator::DGreat) => RedirectKind::Append,
+            TokenKind::Operator(Operator::Clobber) => RedirectKind::Clobber,
+            TokenKind::Operator(Operator::LessGreat) => RedirectKind::ReadWrite,
+            TokenKind::Operator(Operator::LessAnd) => RedirectKind::DupInput,
+            TokenKind::Operator(Operator::GreatAnd) => RedirectKind::DupOutput,
+            TokenKind::Operator(Operator::AndGreat) => RedirectKind::OutputAll,
+            TokenKind::Operator(Operator::AndDGreat) => RedirectKind::AppendAll,
+            TokenKind::Operator(Operator::DLess | Operator::DLessDash) => RedirectKind::HereDoc,
+            TokenKind::Operator(Operator::TLess) => RedirectKind::HereString,
+            _ => return Err(self.unexpected(&token)),
+        };
+
+        let target = self.next()?;
+        let TokenKind::Word(word) = target.kind else {
+            return Err(self.unexpected(&target));
+        };
+        let span = start.to(word.span);
+        // The target of a here-document is its body, not the delimiter
+        let target = match kind {
+            RedirectKind::HereDoc => {
+                let strip_tabs = token.kind == TokenKind::Operator(Operator::DLessDash);
+                self.lexer.read_heredoc(&word, strip_tabs)?
+            }
+            _ => word,
+        };
+        Ok(Redirect { fd, kind, target, span })
+    }
+}
+
+/// Reserved words that end the list before them, such as the `then` after an `if`
+/// condition
+const CLOSING_WORDS: &[&str] = &["}", "then", "elif", "else", "fi", "do", "done", "esac"];
+
+/// Splits the expression of `for (( init; condition; step ))` at its two `;`
+fn split_arithmetic_for(expr: &Word) -> Option<[Word; 3]> {
+    let mut words = vec![Vec::new()];
+    for part in &expr.parts {
+        let WordPart::Literal(text) = part else {
+            words.last_mut().unwrap().push(part.clone());
+            continue;
+        };
+        for (i, piece) in text.split(';').enumerate() {
+            if i > 0 {
+                words.push(Vec::new());
+            }
+            if !piece.is_empty() {
+                words.last_mut().unwrap().push(WordPart::Literal(piece.to_string()));
+            }
+        }
+    }
+    let words: Vec<Word> = words.into_iter().map(|parts| Word { parts, span: expr.span }).collect();
+    words.try_into().ok()
+}
+
+/// Returns the name being defined if `command` could be the start of `name ( )`
+fn function_name(command: &SimpleCommand) -> Option<&str> {
+    match (command.assignments.as_slice(), command.words.as_slice(), command.redirects.as_slice()) {
+        ([], [word], []) => word.as_literal().filter(|name| !name.contains('=')),
+        _ => None,
+    }
+}
+
+/// Builtins whose `NAME=(...)` arguments assign arrays
+fn is_declaration_builtin(name: &str) -> bool {
+    matches!(name, "declare" | "local" | "export" | "readonly")
+}
+
+fn is_cond_binary(op: &str) -> bool {
+    op == "=~" || conditional::is_binary_operator(op)
+}
+
+/// Returns true if `name` is a valid shell variable name
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Recognises `NAME=value`, `NAME+=value` and `NAME[index]=value` words; all but the
+/// subscript must be unquoted up to the `=`
+fn split_assignment(word: &Word) -> Option<Assignment> {
+    let WordPart::Literal(first) = word.parts.first()? else {
+        return None;
+    };
+    let name_len = first.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(first.len());
+    let name = &first[..name_len];
+    if !is_valid_name(name) {
+        return None;
+    }
+
+    let (index, part, offset) = if first[name_len..].starts_with('[') {
+        let (index, part, offset) = split_subscript(&word.parts, name_len + 1)?;
+        (Some(index), part, offset)
+    } else {
+        (None, 0, name_len)
+    };
+    let WordPart::Literal(text) = &word.parts[part] else {
+        return None;
+    };
+    let (append, value) = match text[offset..].strip_prefix("+=") {
+        Some(value) => (true, value),
+        None => (false, text[offset..].strip_prefix('=')?),
+    };
+
+    let mut parts = Vec::new();
+    if !value.is_empty() {
+        parts.push(WordPart::Literal(value.to_string()));
+    }
+    parts.extend(word.parts[part + 1..].iter().cloned());
+
+    let value_start = if index.is_some() { word.span.start } else { word.span.start + text.len() - value.len() };
+    Some(Assignment {
+        name: name.to_string(),
+        index,
+        append,
+        value: Word { parts, span: Span::new(value_start, word.span.end) },
+        span: word.span,
+    })
+}
+
+/// Splits a `[index]=value` element of an array list into its subscript and value
+pub fn split_array_element(word: &Word) -> Option<(Word, Word)> {
+    match word.parts.first()? {
+        WordPart::Literal(first) if first.starts_with('[') => {}
+        _ => return None,
+    }
+    let (index, part, offset) = split_subscript(&word.parts, 1)?;
+    let WordPart::Literal(text) = &word.parts[part] else {
+        return None;
+    };
+    let value = text[offset..].strip_prefix('=')?;
+    let mut parts = Vec::new();
+    if !value.is_empty() {
+        parts.push(WordPart::Literal(value.to_string()));
+    }
+    parts.extend(word.parts[part + 1..].iter().cloned());
+    Some((index, Word { parts, span: word.span }))
+}
+
+/// Collects the subscript of `NAME[index]=value`, which starts `offset` bytes into
+/// the first part and may contain expansions, up to the `]` before `=` or `+=`.
+/// Returns it with the part and offset that the rest of the word starts at
+fn split_subscript(parts: &[WordPart], offset: usize) -> Option<(Word, usize, usize)> {
+    let mut subscript = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let WordPart::Literal(text) = part else {
+            subscript.push(part.clone());
+            continue;
+        };
+        let start = if i == 0 { offset } else { 0 };
+        let text = &text[start..];
+        let end = text
+            .match_indices(']')
+            .map(|(end, _)| end)
+            .find(|&end| text[end + 1..].starts_with('=') || text[end + 1..].starts_with("+="));
+        match end {
+            Some(end) => {
+                if end > 0 {
+                    subscript.push(WordPart::Literal(text[..end].to_string()));
+                }
+                if subscript.is_empty() {
+                    return None;
+                }
+                return Some((Word { parts: subscript, span: Span::default() }, i, start + end + 1));
+            }
+            None if !text.is_empty() => subscript.push(WordPart::Literal(text.to_string())),
+            None => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_command(input: &str) -> Command {
+        let list = parse(input).unwrap();
+        list.items[0].and_or.first.commands[0].clone()
+    }
+
+    fn words(input: &str) -> Vec<Vec<WordPart>> {
+        match first_command(input) {
+            Command::Simple(simple) => simple.words.into_iter().map(|word| word.parts).collect(),
+            command => panic!("not a simple command: {:?}", command),
+        }
+    }
+
+    fn compound(input: &str) -> CompoundCommand {
+        match first_command(input) {
+            Command::Compound(compound, _) => compound,
+            command => panic!("not a compound command: {:?}", command),
+        }
+    }
+
+    fn literal(text: &str) -> WordPart {
+        WordPart::Literal(text.to_string())
+    }
+
+    fn quoted(text: &str) -> WordPart {
+        WordPart::Quoted(text.to_string())
+    }
+
+    fn param(name: &str) -> WordPart {
+        WordPart::Param(ParamExpansion { name: name.to_string(), index: None, op: ParamOp::Value, braced: false })
+    }
+
+    #[test]
+    fn quoting_keeps_each_part_apart() {
+        let words = words(r#"echo 'a b' "c $x" \d $'e\tf' a'b'"c""#);
+        assert_eq!(words[1], vec![quoted("a b")]);
+        assert_eq!(words[2], vec![WordPart::DoubleQuoted(vec![literal("c "), param("x")])]);
+        assert_eq!(words[3], vec![quoted("d")]);
+        assert_eq!(words[4], vec![quoted("e\tf")]);
+        assert_eq!(words[5], vec![literal("a"), quoted("b"), WordPart::DoubleQuoted(vec![literal("c")])]);
+    }
+
+    #[test]
+    fn unterminated_quotes_ask_for_more_input() {
+        assert_eq!(parse("echo 'a"), Err(ParseError::UnterminatedQuote('\'')));
+        assert_eq!(parse("echo \"a"), Err(ParseError::UnterminatedQuote('"')));
+        assert!(needs_more_input("echo 'a\n"));
+        assert!(!needs_more_input("echo 'a'\n"));
+    }
+
+    fn here_doc(input: &str) -> Word {
+        let Command::Simple(simple) = first_command(input) else {
+            panic!("not a simple command");
+        };
+        assert_eq!(simple.redirects[0].kind, RedirectKind::HereDoc);
+        simple.redirects[0].target.clone()
+    }
+
+    #[test]
+    fn here_documents() {
+        // The body is expanded like text in double quotes, without field splitting
+        let body = here_doc("cat <<EOF\nhello $x\nEOF\n");
+        assert_eq!(body.parts, vec![WordPart::DoubleQuoted(vec![literal("hello "), param("x"), literal("\n")])]);
+
+        let body = here_doc("cat <<'EOF'\nhello $x\nEOF\n");
+        assert_eq!(body.parts, vec![quoted("hello $x\n")]);
+
+        let body = here_doc("cat <<-EOF\n\tindented\n\tEOF\n");
+        assert_eq!(body.parts, vec![WordPart::DoubleQuoted(vec![literal("indented\n")])]);
+
+        assert_eq!(parse("cat <<EOF\nhello\n"), Err(ParseError::UnterminatedHereDoc("EOF".to_string())));
+        assert!(needs_more_input("cat <<EOF\nhello\n"));
+    }
+
+    #[test]
+    fn double_parentheses_are_arithmetic_only_when_closed_by_them() {
+        assert!(matches!(compound("((x = 1 + 2))"), CompoundCommand::Arithmetic(_)));
+
+        let CompoundCommand::Subshell(outer) = compound("( (echo a) )") else {
+            panic!("not a subshell");
+        };
+        assert!(matches!(outer.items[0].and_or.first.commands[0], Command::Compound(CompoundCommand::Subshell(_), _)));
+
+        let CompoundCommand::Subshell(outer) = compound("((echo a) )") else {
+            panic!("not a subshell");
+        };
+        assert!(matches!(outer.items[0].and_or.first.commands[0], Command::Compound(CompoundCommand::Subshell(_), _)));
+    }
+
+    #[test]
+    fn case_terminators() {
+        let CompoundCommand::Case { items, .. } = compound("case x in a) echo 1;; b) echo 2;& c) echo 3;;& d) echo 4\nesac")
+        else {
+            panic!("not a case command");
+        };
+        let terminators: Vec<CaseTerminator> = items.iter().map(|item| item.terminator).collect();
+        assert_eq!(
+            terminators,
+            [CaseTerminator::Break, CaseTerminator::FallThrough, CaseTerminator::Continue, CaseTerminator::Break]
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use crate::ast::{Redirect, RedirectKind};
+use crate::error::RedirectionError;
+use crate::shell::Shell;
+use crate::signals;
+
+/// What one file descriptor of a command refers to
+#[derive(Debug, Clone)]
+pub enum Fd {
+    /// The shell process's own descriptor with this number, normally the terminal
+    Inherit(u32),
+    File(Arc<File>),
+    Closed,
+}
+
+/// The file descriptors a command runs with
+#[derive(Debug, Clone)]
+pub struct FdTable {
+    fds: BTreeMap<u32, Fd>,
+}
+
+impl Default for FdTable {
+    fn default() -> FdTable {
+        let fds = (0..3).map(|fd| (fd, Fd::Inherit(fd))).collect();
+        FdTable { fds }
+    }
+}
+
+impl FdTable {
+    pub fn get(&self, fd: u32) -> Fd {
+        self.fds.get(&fd).cloned().unwrap_or(Fd::Closed)
+    }
+
+    pub fn set(&mut self, fd: u32, target: Fd) {
+        self.fds.insert(fd, target);
+    }
+
+    pub fn set_file(&mut self, fd: u32, file: File) {
+        self.set(fd, Fd::File(Arc::new(file)));
+    }
+
+    /// Makes `fd` a copy of `source`, as in `fd>&source`
+    fn duplicate(&mut self, fd: u32, source: u32) -> Result<(), RedirectionError> {
+        match self.get(source) {
+            Fd::Closed => Err(RedirectionError::BadDescriptor(source.to_string())),
+            target => {
+                self.set(fd, target);
+                Ok(())
+            }
+        }
+    }
+
+    /// Opens the descriptor as a standalone file handle
+    pub fn file(&self, fd: u32) -> io::Result<Option<File>> {
+        match self.get(fd) {
+            Fd::Inherit(n) => inherited_file(n).map(Some),
+            Fd::File(file) => file.try_clone().map(Some),
+            Fd::Closed => Ok(None),
+        }
+    }
+
+    pub fn output(&self, fd: u32) -> Output {
+        match self.get(fd) {
+            Fd::Inherit(1) => Output::Stdout,
+            Fd::Inherit(2) => Output::Stderr,
+            Fd::Inherit(n) => inherited_file(n).map_or(Output::Closed, |file| Output::File(Arc::new(file))),
+            Fd::File(file) => Output::File(file),
+            Fd::Closed => Output::Closed,
+        }
+    }
+
+    pub fn input(&self, fd: u32) -> Input {
+        match self.get(fd) {
+            Fd::Inherit(0) => Input::Stdin,
+            Fd::Inherit(n) => inherited_file(n).map_or(Input::Closed, |file| Input::File(Arc::new(file))),
+            Fd::File(file) => Input::File(file),
+            Fd::Closed => Input::Closed,
+        }
+    }
+
+    fn stdio(&self, fd: u32) -> io::Result<Stdio> {
+        Ok(match self.get(fd) {
+            Fd::Inherit(n) if n == fd => Stdio::inherit(),
+            _ => match self.file(fd)? {
+                Some(file) => Stdio::from(file),
+                None => Stdio::null(),
+            },
+        })
+    }
+
+    /// Makes a child process start with these descriptors
+    pub fn apply_to(&self, cmd: &mut Command) -> io::Result<()> {
+        cmd.stdin(self.stdio(0)?).stdout(self.stdio(1)?).stderr(self.stdio(2)?);
+
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            use std::os::unix::process::CommandExt;
+
+            let mut extra = Vec::new();
+            for &fd in self.fds.keys().filter(|&&fd| fd > 2) {
+                if let Some(file) = self.file(fd)? {
+                    extra.push((file, fd as i32));
+                }
+            }
+            // Closed standard descriptors get `/dev/null` above and are closed for real
+            // just before exec
+            let closed: Vec<i32> = (0..3).filter(|&fd| matches!(self.get(fd), Fd::Closed)).map(|fd| fd as i32).collect();
+            if !extra.is_empty() || !closed.is_empty() {
+                let above = extra.iter().map(|&(_, target)| target).max().unwrap_or(2) + 1;
+                // Allocated here, as the child must not allocate
+                let mut moved = Vec::with_capacity(extra.len());
+                // SAFETY: only async-signal-safe calls are made between fork and exec
+                unsafe {
+                    cmd.pre_exec(move || {
+                        // Every source is first moved above all the targets, so that no
+                        // `dup2` overwrites a source still to be copied
+                        moved.clear();
+                        for (file, _) in &extra {
+                            let source = libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, above);
+                            if source < 0 {
+                                return Err(io::Error::last_os_error());
+                            }
+                            moved.push(source);
+                        }
+                        for (&source, (_, target)) in moved.iter().zip(&extra) {
+                            if libc::dup2(source, *target) < 0 {
+                                return Err(io::Error::last_os_error());
+                            }
+                        }
+                        for &fd in &closed {
+                            libc::close(fd);
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writable handle on one of a command's descriptors, used by builtins
+pub enum Output {
+    Stdout,
+    Stderr,
+    File(Arc<File>),
+    Closed,
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout => io::stdout().write(buf),
+            Output::Stderr => io::stderr().write(buf),
+            Output::File(file) => (&**file).write(buf),
+            Output::Closed => Err(bad_descriptor()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::Stderr => io::stderr().flush(),
+            Output::File(file) => (&**file).flush(),
+            Output::Closed => Ok(()),
+        }
+    }
+}
+
+/// Readable handle on one of a command's descriptors, used by builtins
+pub enum Input {
+    Stdin,
+    File(Arc<File>),
+    Closed,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Stdin => {
+                // A read from the terminal would restart after Ctrl-C and keep waiting.
+                // The error is not `Interrupted`, which readers retry
+                if io::stdin().is_terminal() && signals::wait_for_input(0).is_err() {
+                    return Err(io::Error::other("interrupted"));
+                }
+                io::stdin().read(buf)
+            }
+            Input::File(file) => (&**file).read(buf),
+            Input::Closed => Err(bad_descriptor()),
+        }
+    }
+}
+
+fn bad_descriptor() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Bad file descriptor")
+}
+
+/// Duplicates one of the shell's standard descriptors
+fn inherited_file(fd: u32) -> io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        let owned = match fd {
+            0 => io::stdin().as_fd().try_clone_to_owned()?,
+            1 => io::stdout().as_fd().try_clone_to_owned()?,
+            2 => io::stderr().as_fd().try_clone_to_owned()?,
+            _ => return Err(bad_descriptor()),
+        };
+        Ok(File::from(owned))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsHandle;
+        let owned = match fd {
+            0 => io::stdin().as_handle().try_clone_to_owned()?,
+            1 => io::stdout().as_handle().try_clone_to_owned()?,
+            2 => io::stderr().as_handle().try_clone_to_owned()?,
+            _ => return Err(bad_descriptor()),
+        };
+        Ok(File::from(owned))
+    }
+}
+
+/// Creates an anonymous pipe, returning its read and write ends
+pub fn pipe() -> io::Result<(File, File)> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::FromRawFd;
+        let mut fds = [0; 2];
+        // Commands started later must not hold on to the ends they were not given.
+        // Other threads start commands too, so the ends are close-on-exec from the
+        // moment they exist
+        // SAFETY: `pipe2` and `pipe` only fill in `fds`
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        let created = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let created = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        if created < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptors are new, and each file becomes their only owner
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        // Without `pipe2` the flag can only be set afterwards
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        for fd in fds {
+            // SAFETY: `fd` is open, owned by one of the files above
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok((reader, writer))
+    }
+    #[cfg(windows)]
+    {
+        use std::ffi::c_void;
+        use std::os::windows::io::{FromRawHandle, RawHandle};
+        extern "system" {
+            fn CreatePipe(read: *mut RawHandle, write: *mut RawHandle, attributes: *mut c_void, size: u32) -> i32;
+        }
+        let (mut reader, mut writer) = (std::ptr::null_mut(), std::ptr::null_mut());
+        // SAFETY: `CreatePipe` fills in two new handles, which the files then own
+        unsafe {
+            if CreatePipe(&mut reader, &mut writer, std::ptr::null_mut(), 0) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok((File::from_raw_handle(reader), File::from_raw_handle(writer)))
+        }
+    }
+}
+
+/// Returns the read end of a pipe that supplies `text`. A thread writes it so
+/// that bodies larger than the pipe buffer do not block the shell
+fn here_document(text: String) -> io::Result<File> {
+    let (reader, writer) = pipe()?;
+    thread::spawn(move || {
+        // The reader may exit without reading everything
+        let _ = (&writer).write_all(text.as_bytes());
+    });
+    Ok(reader)
+}
+
+impl Shell {
+    /// Standard output of the running command
+    pub fn stdout(&self) -> Output {
+        self.fds.output(1)
+    }
+
+    /// Standard error of the running command
+    pub fn stderr(&self) -> Output {
+        self.fds.output(2)
+    }
+
+    /// Standard input of the running command
+    pub fn stdin(&self) -> Input {
+        self.fds.input(0)
+    }
+
+    /// Expands the target word of every redirection
+    pub fn expand_redirect_targets(&mut self, redirects: &[Redirect]) -> io::Result<Vec<String>> {
+        redirects.iter().map(|redirect| self.expand_word(&redirect.target)).collect()
+    }
+
+    /// With `noclobber`, refuses to truncate an existing regular file; devices such
+    /// as `/dev/null` can still be written
+    fn check_clobber(&self, path: &Path, target: &str) -> Result<(), RedirectionError> {
+        if self.options.noclobber && fs::metadata(path).is_ok_and(|meta| meta.is_file()) {
+            return Err(RedirectionError::Clobber(target.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Applies redirections in order on top of `base`, returning the descriptors a
+    /// command runs with
+    pub fn open_redirects(&self, redirects: &[Redirect], targets: &[String], base: &FdTable) -> Result<FdTable, RedirectionError> {
+        let mut table = base.clone();
+        for (redirect, target) in redirects.iter().zip(targets) {
+            let default_fd = match redirect.kind {
+                RedirectKind::Input
+                | RedirectKind::ReadWrite
+                | RedirectKind::DupInput
+                | RedirectKind::HereDoc
+                | RedirectKind::HereString => 0,
+                _ => 1,
+            };
+            let fd = redirect.fd.unwrap_or(default_fd);
+            let path = self.current_dir.join(target);
+            let mut options = OpenOptions::new();
+
+            match redirect.kind {
+                RedirectKind::Input => {
+                    options.read(true);
+                }
+                RedirectKind::Output | RedirectKind::Clobber | RedirectKind::OutputAll => {
+                    if redirect.kind != RedirectKind::Clobber {
+                        self.check_clobber(&path, target)?;
+                    }
+                    options.write(true).create(true).truncate(true);
+                }
+                RedirectKind::Append | RedirectKind::AppendAll => {
+                    options.append(true).create(true);
+                }
+                RedirectKind::ReadWrite => {
+                    options.read(true).write(true).create(true).truncate(false);
+                }
+                RedirectKind::DupInput | RedirectKind::DupOutput => {
+                    if target == "-" {
+                        table.set(fd, Fd::Closed);
+                        continue;
+                    }
+                    if let Ok(source) = target.parse::<u32>() {
+                        table.duplicate(fd, source)?;
+                        continue;
+                    }
+                    // `>&file` is another spelling of `&>file`
+                    if redirect.kind == RedirectKind::DupInput || redirect.fd.is_some() {
+                        return Err(RedirectionError::Ambiguous(target.clone()));
+                    }
+                    self.check_clobber(&path, target)?;
+                    options.write(true).create(true).truncate(true);
+                }
+                RedirectKind::HereDoc | RedirectKind::HereString => {
+                    let mut text = target.clone();
+                    if redirect.kind == RedirectKind::HereString {
+                        text.push('\n');
+                    }
+                    table.set_file(fd, here_document(text).map_err(RedirectionError::HereDoc)?);
+                    continue;
+                }
+            }
+
+            let file = options
+                .open(&path)
+                .map_err(|source| RedirectionError::Open { path: target.clone(), source })?;
+            let file = Fd::File(Arc::new(file));
+
+            match redirect.kind {
+                RedirectKind::OutputAll | RedirectKind::AppendAll | RedirectKind::DupOutput => {
+                    table.set(1, file.clone());
+                    table.set(2, file);
+                }
+                _ => table.set(fd, file),
+            }
+        }
+        Ok(table)
+    }
+}
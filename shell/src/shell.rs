@@ -4,20 +4,73 @@ use std::env;
 use std::fs::File;
 use std::io::{self, Write, BufReader, BufRead};
 use std::path::{Path, PathBuf, Component};
-use dirs;
-use hostname;
 use std::fs;
+use std::sync::Arc;
 use glob::Pattern;
+use crate::ast::FunctionDef;
+use crate::control_flow::Flow;
+use crate::error::{BuiltinError, BuiltinResult};
+use crate::jobs::{JobTable, ProcessGroup, Terminal};
+use crate::options::ShellOptions;
+use crate::parser;
+use crate::redirection::{FdTable, Output};
 use crate::shell_type::ShellType;
+use crate::signals;
+use crate::traps::{Trap, Traps};
+use crate::variables::{Variable, Variables};
 
+/// Signature shared by every builtin command
+pub type BuiltinFn = fn(&mut Shell, &[String]) -> BuiltinResult;
+
+#[derive(Clone)]
 pub struct Shell {
     pub shell_type: ShellType,
     pub current_dir: PathBuf,
-    pub env_vars: HashMap<String, String>,
-    pub builtins: HashMap<String, fn(&mut Shell, &[String]) -> io::Result<()>>,
+    pub variables: Variables,
+    pub builtins: HashMap<String, BuiltinFn>,
+    /// Functions defined with `name() { ... }`, looked up before the builtins
+    pub functions: HashMap<String, Arc<FunctionDef>>,
     pub home_dir: PathBuf,
     pub history: Vec<String>,
     pub history_file: PathBuf,
+    /// Exit status of the most recently executed pipeline (`$?`)
+    pub last_status: i32,
+    /// Process ID of the most recent background command (`$!`)
+    pub last_background_pid: Option<u32>,
+    /// Background and stopped jobs that have not been reported as finished
+    pub jobs: JobTable,
+    /// Group that the processes this shell starts join; set while a job runs
+    pub process_group: Option<ProcessGroup>,
+    /// The terminal, when the interactive shell does job control; copies of the
+    /// shell running in threads leave it to the shell
+    pub terminal: Option<Terminal>,
+    /// Name of the shell or running script (`$0`)
+    pub shell_name: String,
+    /// Positional parameters `$1`, `$2`, ...
+    pub positional_params: Vec<String>,
+    /// Descriptors commands inherit; changed while a redirected compound command runs
+    pub fds: FdTable,
+    pub options: ShellOptions,
+    /// A `break` or `continue` on its way out to the loop it applies to
+    pub flow: Option<Flow>,
+    /// Number of loops currently running, the most `break` and `continue` can leave
+    pub loop_depth: usize,
+    /// Values the `local` variables of each running function replaced, innermost
+    /// call last; `None` means the variable was unset
+    pub local_scopes: Vec<HashMap<String, Option<Variable>>>,
+    /// Number of files being read by `source`, which `return` can leave
+    pub source_depth: usize,
+    /// Commands set with `trap`
+    pub traps: Traps,
+    /// Set in copies of the shell for subshells and threads. They share the process's
+    /// signal dispositions with the shell, so their traps are kept to themselves
+    pub in_subshell: bool,
+    /// Set while a trap's command runs, which sets off no `DEBUG` or `ERR` trap
+    pub running_trap: bool,
+    /// Number of conditions being run, of `if`, `while` and `until` or tested by `&&`,
+    /// `||` and `!`, whose failures neither set off the `ERR` trap nor end the shell
+    /// under `set -e`
+    pub condition_depth: usize,
 }
 
 impl Shell {
@@ -29,24 +82,56 @@ impl Shell {
             ShellType::Windows => "Windows",
         });
 
+        let mut shell = Shell::without_history(shell_type)?;
+        shell.load_history()?;
+        Ok(shell)
+    }
+
+    /// A shell for unit tests, which neither prints nor reads the history file
+    #[cfg(test)]
+    pub fn for_tests() -> Shell {
+        Shell::without_history(ShellType::Linux).expect("the current directory is readable")
+    }
+
+    /// Sets up the shell's state, with an empty history
+    fn without_history(shell_type: ShellType) -> io::Result<Shell> {
         let mut builtins = HashMap::new();
-        builtins.insert("cd".to_string(), Shell::cd as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("echo".to_string(), Shell::echo as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("pwd".to_string(), Shell::pwd as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("type".to_string(), Shell::type_cmd as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("ls".to_string(), Shell::ls as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("clear".to_string(), Shell::clear as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("env".to_string(), Shell::env as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("which".to_string(), Shell::which as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("history".to_string(), Shell::history as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("source".to_string(), Shell::source as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("cat".to_string(), Shell::cat as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("mkdir".to_string(), Shell::mkdir as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("touch".to_string(), Shell::touch as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("grep".to_string(), Shell::grep as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("find".to_string(), Shell::find as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("head".to_string(), Shell::head as fn(&mut Shell, &[String]) -> io::Result<()>);
-        builtins.insert("tail".to_string(), Shell::tail as fn(&mut Shell, &[String]) -> io::Result<()>);
+        builtins.insert("cd".to_string(), Shell::cd as BuiltinFn);
+        builtins.insert("echo".to_string(), Shell::echo as BuiltinFn);
+        builtins.insert("pwd".to_string(), Shell::pwd as BuiltinFn);
+        builtins.insert("type".to_string(), Shell::type_cmd as BuiltinFn);
+        builtins.insert("ls".to_string(), Shell::ls as BuiltinFn);
+        builtins.insert("clear".to_string(), Shell::clear as BuiltinFn);
+        builtins.insert("env".to_string(), Shell::env as BuiltinFn);
+        builtins.insert("which".to_string(), Shell::which as BuiltinFn);
+        builtins.insert("history".to_string(), Shell::history as BuiltinFn);
+        builtins.insert("source".to_string(), Shell::source as BuiltinFn);
+        builtins.insert("break".to_string(), Shell::break_cmd as BuiltinFn);
+        builtins.insert("continue".to_string(), Shell::continue_cmd as BuiltinFn);
+        builtins.insert("return".to_string(), Shell::return_cmd as BuiltinFn);
+        builtins.insert("exit".to_string(), Shell::exit_cmd as BuiltinFn);
+        builtins.insert("local".to_string(), Shell::local as BuiltinFn);
+        builtins.insert("export".to_string(), Shell::export as BuiltinFn);
+        builtins.insert("readonly".to_string(), Shell::readonly as BuiltinFn);
+        builtins.insert("declare".to_string(), Shell::declare as BuiltinFn);
+        builtins.insert("unset".to_string(), Shell::unset as BuiltinFn);
+        builtins.insert("test".to_string(), Shell::test as BuiltinFn);
+        builtins.insert("[".to_string(), Shell::bracket as BuiltinFn);
+        builtins.insert("jobs".to_string(), Shell::jobs_cmd as BuiltinFn);
+        builtins.insert("fg".to_string(), Shell::fg as BuiltinFn);
+        builtins.insert("bg".to_string(), Shell::bg as BuiltinFn);
+        builtins.insert("wait".to_string(), Shell::wait_cmd as BuiltinFn);
+        builtins.insert("disown".to_string(), Shell::disown as BuiltinFn);
+        builtins.insert("trap".to_string(), Shell::trap as BuiltinFn);
+        builtins.insert("set".to_string(), Shell::set as BuiltinFn);
+        builtins.insert("shopt".to_string(), Shell::shopt as BuiltinFn);
+        builtins.insert("cat".to_string(), Shell::cat as BuiltinFn);
+        builtins.insert("mkdir".to_string(), Shell::mkdir as BuiltinFn);
+        builtins.insert("touch".to_string(), Shell::touch as BuiltinFn);
+        builtins.insert("grep".to_string(), Shell::grep as BuiltinFn);
+        builtins.insert("find".to_string(), Shell::find as BuiltinFn);
+        builtins.insert("head".to_string(), Shell::head as BuiltinFn);
+        builtins.insert("tail".to_string(), Shell::tail as BuiltinFn);
         
         let current_dir = env::current_dir()?;
         let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
@@ -55,71 +140,122 @@ impl Shell {
             _ => ".shell_history",
         });
 
-        let mut shell = Shell {
+        Ok(Shell {
             shell_type,
             current_dir,
-            env_vars: env::vars().collect(),
+            variables: Variables::from_env(),
             builtins,
+            functions: HashMap::new(),
             home_dir,
             history: Vec::new(),
             history_file,
-        };
-
-        shell.load_history()?;
-        Ok(shell)
+            last_status: 0,
+            last_background_pid: None,
+            jobs: JobTable::default(),
+            process_group: None,
+            terminal: None,
+            shell_name: env::args().next().unwrap_or_else(|| "shell".to_string()),
+            positional_params: Vec::new(),
+            fds: FdTable::default(),
+            options: ShellOptions::default(),
+            flow: None,
+            loop_depth: 0,
+            local_scopes: Vec::new(),
+            source_depth: 0,
+            traps: Traps::default(),
+            running_trap: false,
+            condition_depth: 0,
+            in_subshell: false,
+        })
     }
 
     
-    /// The loop continues until 'exit' is entered or EOF is received
-    pub fn run(&mut self) -> io::Result<()> {
+    /// The loop continues until 'exit' is entered, EOF is received or a failed command
+    /// ends the shell under `set -e`, whose status is returned to exit with.
+    /// Lines are read until they form a complete command, prompting with `> `
+    pub fn run(&mut self) -> io::Result<i32> {
         println!("Shell is running. Type 'exit' to quit.");
-        
+        self.terminal = Terminal::init();
+        if self.terminal.is_some() {
+            signals::catch_signals();
+        }
+
+        let mut input = String::new();
+        let mut exit_status = 0;
         loop {
-            print!("{}", self.get_prompt());
+            if input.is_empty() {
+                self.run_pending_traps();
+                self.notify_jobs();
+                print!("{}", self.get_prompt());
+            } else {
+                print!("> ");
+            }
             if let Err(e) = io::stdout().flush() {
                 eprintln!("Error flushing stdout: {}", e);
                 continue;
             }
 
-            let mut input = String::new();
-            match io::stdin().read_line(&mut input) {
+            let read = match self.terminal {
+                Some(terminal) => terminal.read_line(&mut input),
+                None => io::stdin().read_line(&mut input),
+            };
+            match read {
                 Ok(n) => {
-                    if n == 0 {
+                    if n == 0 && input.is_empty() {
                         println!("Received EOF (Ctrl+D), exiting...");
                         break;
                     }
+                    // At EOF an unfinished command is run anyway to report the error
+                    if n > 0 && parser::needs_more_input(&input) {
+                        continue;
+                    }
+                }
+                // Ctrl-C throws away the command being typed
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    println!();
+                    input.clear();
+                    continue;
                 }
                 Err(e) => {
                     eprintln!("Error reading from stdin: {}", e);
+                    input.clear();
                     continue;
                 }
             }
 
-            let input = input.trim();
-            if input.is_empty() {
+            let command = std::mem::take(&mut input);
+            let command = command.trim();
+            if command.is_empty() {
                 continue;
             }
 
-            if input == "exit" {
-                println!("Exit command received, shutting down...");
-                break;
-            }
-
             // Process the command
-            self.add_to_history(input);
-            if let Err(e) = self.execute_command(input) {
+            self.add_to_history(command);
+            if self.options.verbose {
+                eprintln!("{}", command);
+            }
+            signals::take_interrupt();
+            if let Err(e) = self.execute_command(command) {
                 eprintln!("Error executing command: {}", e);
             }
+            // The terminal echoed `^C` where the prompt would go
+            if signals::take_interrupt() {
+                println!();
+            }
+            if let Some(Flow::Exit(status)) = self.flow {
+                exit_status = status;
+                break;
+            }
         }
-        Ok(())
+        self.run_trap(Trap::Exit);
+        Ok(exit_status)
     }
 
 
     /// - Shell-specific prompt character ($ for Linux, % for MacOS, > for Windows)
     pub fn get_prompt(&self) -> String {
         let display_path = self.format_display_path();
-        let default_user = String::from("user");
-        let username = self.env_vars.get("USER").unwrap_or(&default_user);
+        let username = self.variables.get("USER").unwrap_or("user");
         let hostname = hostname::get().unwrap_or_default().to_string_lossy().to_string();
 
         match self.shell_type {
@@ -150,10 +286,8 @@ impl Shell {
         if self.history_file.exists() {
             let file = File::open(&self.history_file)?;
             let reader = BufReader::new(file);
-            for line in reader.lines() {
-                if let Ok(command) = line {
-                    self.history.push(command);
-                }
+            for command in reader.lines().map_while(Result::ok) {
+                self.history.push(command);
             }
         }
         Ok(())
@@ -179,6 +313,12 @@ impl Shell {
         }
     }
 
+    /// Resolves a path given to a command against the shell's current directory,
+    /// which is not the process's working directory
+    pub fn resolve_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.current_dir.join(path)
+    }
+
     /// Normalizes a path by resolving parent directory references (..)
     /// and removing redundant components
     /// Returns a cleaned up PathBuf
@@ -195,101 +335,113 @@ impl Shell {
         components.iter().collect()
     }
 
-    pub fn find(&mut self, args: &[String]) -> io::Result<()> {
-        let dir = args.get(0).map(Path::new).unwrap_or_else(|| Path::new("."));
-        let pattern = args.get(1).map(|s| Pattern::new(s).ok()).flatten();
+    pub fn find(&mut self, args: &[String]) -> BuiltinResult {
+        let dir = args.first().map(Path::new).unwrap_or_else(|| Path::new("."));
+        let pattern = args.get(1).and_then(|s| Pattern::new(s).ok());
 
-        Self::find_recursive(dir, pattern.as_ref())?;
-        Ok(())
+        self.find_recursive(&self.resolve_path(dir), dir, pattern.as_ref(), &mut self.stdout())?;
+        Ok(0)
     }
 
-    fn find_recursive(dir: &Path, pattern: Option<&Pattern>) -> io::Result<()> {
-        for entry in fs::read_dir(dir)? {
+    /// Lists the entries below `dir`, which is shown as `shown`
+    fn find_recursive(&self, dir: &Path, shown: &Path, pattern: Option<&Pattern>, out: &mut Output) -> Result<(), BuiltinError> {
+        let entries = fs::read_dir(dir).map_err(|e| BuiltinError::path(shown.display().to_string(), e))?;
+        for entry in entries {
+            self.check_interrupt()?;
             let entry = entry?;
-            let path = entry.path();
+            let path = shown.join(entry.file_name());
             
             if let Some(pattern) = pattern {
                 if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
                     if pattern.matches(name) {
-                        println!("{}", path.display());
+                        writeln!(out, "{}", path.display())?;
                     }
                 }
             } else {
-                println!("{}", path.display());
+                writeln!(out, "{}", path.display())?;
             }
 
-            if path.is_dir() {
-                Self::find_recursive(&path, pattern)?;
+            if entry.path().is_dir() {
+                self.find_recursive(&entry.path(), &path, pattern, out)?;
             }
         }
         Ok(())
     }
 
-    pub fn head(&mut self, args: &[String]) -> io::Result<()> {
-        let (file_name, lines) = match args.len() {
-            0 => {
-                eprintln!("Usage: head [-n lines] <file>");
-                return Ok(());
-            }
-            1 => (args[0].as_str(), 10),
-            _ if args[0] == "-n" => {
-                (args[2].as_str(), args[1].parse().unwrap_or(10))
+    /// Parses the `[-n lines] [file]` arguments shared by `head` and `tail`
+    fn parse_line_count_args(args: &[String]) -> Result<(Option<&str>, usize), BuiltinError> {
+        match args {
+            [flag, count, rest @ ..] if flag == "-n" => {
+                let lines = count
+                    .parse()
+                    .map_err(|_| BuiltinError::InvalidArgument(format!("{}: invalid number of lines", count)))?;
+                Ok((rest.first().map(String::as_str), lines))
             }
-            _ => (args[0].as_str(), 10),
-        };
+            [flag] if flag == "-n" => Err(BuiltinError::Usage("option requires an argument -- 'n'".to_string())),
+            [file, ..] => Ok((Some(file.as_str()), 10)),
+            [] => Ok((None, 10)),
+        }
+    }
 
-        let file = File::open(file_name)?;
-        let reader = BufReader::new(file);
-        for (i, line) in reader.lines().enumerate() {
-            if i >= lines {
-                break;
+    /// Opens the named file for reading, or the command's standard input for `None` or `-`
+    fn open_input(&self, file_name: Option<&str>) -> Result<Box<dyn BufRead>, BuiltinError> {
+        match file_name {
+            None | Some("-") => Ok(Box::new(BufReader::new(self.stdin()))),
+            Some(name) => {
+                let file = File::open(self.resolve_path(name)).map_err(|e| BuiltinError::path(name, e))?;
+                Ok(Box::new(BufReader::new(file)))
             }
-            println!("{}", line?);
         }
-        Ok(())
     }
 
-    pub fn grep(&mut self, args: &[String]) -> io::Result<()> {
-        if args.len() < 2 {
-            eprintln!("Usage: grep <pattern> <file>");
-            return Ok(());
+    pub fn head(&mut self, args: &[String]) -> BuiltinResult {
+        let (file_name, lines) = Self::parse_line_count_args(args)?;
+
+        let reader = self.open_input(file_name)?;
+        let mut out = self.stdout();
+        for line in reader.lines().take(lines) {
+            self.check_interrupt()?;
+            writeln!(out, "{}", line?)?;
         }
+        Ok(0)
+    }
 
-        let pattern = &args[0];
-        let file_name = &args[1];
-        let file = File::open(file_name)?;
-        let reader = BufReader::new(file);
+    /// Prints the lines of a file (or standard input) containing the pattern; like grep,
+    /// the status is 1 when nothing matched
+    pub fn grep(&mut self, args: &[String]) -> BuiltinResult {
+        let Some(pattern) = args.first() else {
+            return Err(BuiltinError::Usage("Usage: grep <pattern> [file]".to_string()));
+        };
 
+        let reader = self.open_input(args.get(1).map(String::as_str))?;
+        let mut out = self.stdout();
+        let mut matched = false;
         for line in reader.lines() {
+            self.check_interrupt()?;
             let line = line?;
-            if line.contains(pattern) {
-                println!("{}", line);
+            if line.contains(pattern.as_str()) {
+                writeln!(out, "{}", line)?;
+                matched = true;
             }
         }
-        Ok(())
+        Ok(if matched { 0 } else { 1 })
     }
 
-    pub fn tail(&mut self, args: &[String]) -> io::Result<()> {
-        let (file_name, lines) = match args.len() {
-            0 => {
-                eprintln!("Usage: tail [-n lines] <file>");
-                return Ok(());
-            }
-            1 => (args[0].as_str(), 10),
-            _ if args[0] == "-n" => {
-                (args[2].as_str(), args[1].parse().unwrap_or(10))
-            }
-            _ => (args[0].as_str(), 10),
-        };
+    pub fn tail(&mut self, args: &[String]) -> BuiltinResult {
+        let (file_name, lines) = Self::parse_line_count_args(args)?;
 
-        let file = File::open(file_name)?;
-        let reader = BufReader::new(file);
-        let all_lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
+        let reader = self.open_input(file_name)?;
+        let mut all_lines = Vec::new();
+        for line in reader.lines() {
+            self.check_interrupt()?;
+            all_lines.push(line?);
+        }
         let start = all_lines.len().saturating_sub(lines);
         
+        let mut out = self.stdout();
         for line in &all_lines[start..] {
-            println!("{}", line);
+            writeln!(out, "{}", line)?;
         }
-        Ok(())
+        Ok(0)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,219 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+
+use crate::error::BuiltinError;
+use crate::shell::Shell;
+
+/// Exit status of a command killed by SIGINT
+#[cfg(unix)]
+pub const INTERRUPTED_STATUS: i32 = 128 + libc::SIGINT;
+#[cfg(not(unix))]
+pub const INTERRUPTED_STATUS: i32 = 130;
+
+/// Signals that stop a process from the terminal. The shell ignores them while it
+/// controls the terminal, and the processes of its jobs get the defaults back
+#[cfg(unix)]
+pub const STOP_SIGNALS: [libc::c_int; 3] = [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+/// Set by Ctrl-C, or when a foreground job is killed by it; cleared before each
+/// command line
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set once the interactive shell catches signals for itself
+static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Process group of the foreground job, which gets the SIGINT and SIGQUIT sent to
+/// the shell; 0 while the shell runs commands itself
+static FOREGROUND: AtomicI32 = AtomicI32::new(0);
+
+/// Both ends of a pipe the handler writes to, waking the shell from waiting for input
+static WAKE_READ: AtomicI32 = AtomicI32::new(-1);
+static WAKE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/// Bit masks of the signals that have a trap, and of those that arrived since their
+/// traps last ran
+static TRAPPED: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+fn bit(signal: i32) -> u64 {
+    if (0..64).contains(&signal) {
+        1 << signal
+    } else {
+        0
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    let pgid = FOREGROUND.load(Ordering::SeqCst);
+    if pgid > 0 && (signal == libc::SIGINT || signal == libc::SIGQUIT) {
+        unsafe {
+            libc::kill(-pgid, signal);
+        }
+    }
+    if signal == libc::SIGINT {
+        interrupt();
+        let fd = WAKE_WRITE.load(Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe {
+                libc::write(fd, [1u8].as_ptr().cast(), 1);
+            }
+        }
+    } else if TRAPPED.load(Ordering::SeqCst) & bit(signal) != 0 {
+        PENDING.fetch_or(bit(signal), Ordering::SeqCst);
+    }
+}
+
+/// Sets the handler of a signal, restarting system calls it interrupts
+#[cfg(unix)]
+fn set_handler(signal: i32, handler: libc::sighandler_t) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(unix)]
+fn catching_handler() -> libc::sighandler_t {
+    handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t
+}
+
+/// Catches SIGINT and SIGQUIT so that they no longer kill the interactive shell.
+/// Caught signals get their default dispositions back when a command starts
+pub fn catch_signals() {
+    INTERACTIVE.store(true, Ordering::SeqCst);
+    #[cfg(unix)]
+    unsafe {
+        let mut fds = [-1; 2];
+        if libc::pipe(fds.as_mut_ptr()) == 0 {
+            for fd in fds {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+            WAKE_READ.store(fds[0], Ordering::SeqCst);
+            WAKE_WRITE.store(fds[1], Ordering::SeqCst);
+        }
+        for signal in [libc::SIGINT, libc::SIGQUIT] {
+            set_handler(signal, catching_handler());
+        }
+    }
+}
+
+/// Catches `signal` so that its trap runs, or ignores it for a trap with no command
+pub fn trap_signal(signal: i32, ignore: bool) {
+    if ignore {
+        TRAPPED.fetch_and(!bit(signal), Ordering::SeqCst);
+    } else {
+        TRAPPED.fetch_or(bit(signal), Ordering::SeqCst);
+    }
+    #[cfg(unix)]
+    set_handler(signal, if ignore { libc::SIG_IGN } else { catching_handler() });
+}
+
+/// Removes the trap on `signal`, giving it back the disposition the shell started with
+pub fn untrap_signal(signal: i32) {
+    TRAPPED.fetch_and(!bit(signal), Ordering::SeqCst);
+    PENDING.fetch_and(!bit(signal), Ordering::SeqCst);
+    #[cfg(unix)]
+    {
+        let interactive = INTERACTIVE.load(Ordering::SeqCst);
+        let handler = if interactive && (signal == libc::SIGINT || signal == libc::SIGQUIT) {
+            catching_handler()
+        } else if interactive && STOP_SIGNALS.contains(&signal) {
+            libc::SIG_IGN
+        } else {
+            libc::SIG_DFL
+        };
+        set_handler(signal, handler);
+    }
+}
+
+/// Takes the signals among `signals` that arrived since the last call, lowest first
+pub fn take_pending(signals: &[i32]) -> Vec<i32> {
+    let mask = signals.iter().fold(0, |mask, &signal| mask | bit(signal));
+    let pending = PENDING.fetch_and(!mask, Ordering::SeqCst) & mask;
+    (0..64).filter(|&signal| pending & bit(signal) != 0).collect()
+}
+
+/// Passes SIGINT and SIGQUIT on to `pgid` while it runs in the foreground
+pub fn set_foreground(pgid: Option<u32>) {
+    FOREGROUND.store(pgid.map_or(0, |pgid| pgid as i32), Ordering::SeqCst);
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Makes the shell act as if Ctrl-C had been pressed: a trap on SIGINT runs, or
+/// else what is running is interrupted
+pub fn interrupt() {
+    #[cfg(unix)]
+    if TRAPPED.load(Ordering::SeqCst) & bit(libc::SIGINT) != 0 {
+        PENDING.fetch_or(bit(libc::SIGINT), Ordering::SeqCst);
+        return;
+    }
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Clears a pending interrupt, returning whether there was one
+pub fn take_interrupt() -> bool {
+    drain_wake_pipe();
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+fn drain_wake_pipe() {
+    #[cfg(unix)]
+    {
+        let fd = WAKE_READ.load(Ordering::SeqCst);
+        let mut buffer = [0u8; 64];
+        while fd >= 0 && unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+    }
+}
+
+/// Waits until `fd` can be read. Ctrl-C makes it fail with `Interrupted` instead,
+/// leaving the interrupt set so that the rest of the command line is skipped too
+pub fn wait_for_input(fd: i32) -> io::Result<()> {
+    #[cfg(unix)]
+    loop {
+        drain_wake_pipe();
+        if interrupted() {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        let mut fds = [
+            libc::pollfd { fd, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: WAKE_READ.load(Ordering::SeqCst), events: libc::POLLIN, revents: 0 },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        } else if fds[0].revents != 0 {
+            return Ok(());
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = fd;
+        Ok(())
+    }
+}
+
+impl Shell {
+    /// Whether Ctrl-C has interrupted what this shell is running. Background jobs
+    /// carry on
+    pub fn interrupted(&self) -> bool {
+        interrupted() && self.process_group.as_ref().map_or(true, |group| group.is_foreground())
+    }
+
+    /// Lets a long-running builtin stop when it is interrupted
+    pub fn check_interrupt(&self) -> Result<(), BuiltinError> {
+        if self.interrupted() {
+            return Err(BuiltinError::Interrupted);
+        }
+        Ok(())
+    }
+}
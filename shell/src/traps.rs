@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::Command;
+
+use crate::error::{BuiltinError, BuiltinResult};
+use crate::shell::Shell;
+use crate::signals;
+use crate::utils::quote;
+
+/// Signals that can be named in a trap, without their `SIG` prefix
+#[cfg(unix)]
+const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("SYS", libc::SIGSYS),
+];
+#[cfg(not(unix))]
+const SIGNALS: &[(&str, i32)] = &[];
+
+/// What a trap is set on: a signal, or one of the conditions the shell raises itself.
+/// Traps are listed in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trap {
+    /// The shell, or a subshell, exits
+    Exit,
+    Signal(i32),
+    /// Before each simple command
+    Debug,
+    /// A command fails
+    Err,
+    /// A function or sourced file finishes
+    Return,
+}
+
+impl Trap {
+    /// Parses a condition: `EXIT`, `DEBUG`, `ERR`, `RETURN` or a signal name, with or
+    /// without `SIG` and in any case, or a signal number, 0 being `EXIT`
+    pub fn parse(spec: &str) -> Option<Trap> {
+        if let Ok(number) = spec.parse::<i32>() {
+            return match number {
+                0 => Some(Trap::Exit),
+                _ => SIGNALS.iter().any(|&(_, signal)| signal == number).then_some(Trap::Signal(number)),
+            };
+        }
+        let upper = spec.to_ascii_uppercase();
+        let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+        match name {
+            "EXIT" => Some(Trap::Exit),
+            "DEBUG" => Some(Trap::Debug),
+            "ERR" => Some(Trap::Err),
+            "RETURN" => Some(Trap::Return),
+            _ => SIGNALS.iter().find(|&&(signal_name, _)| signal_name == name).map(|&(_, signal)| Trap::Signal(signal)),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Trap::Exit => "EXIT".to_string(),
+            Trap::Debug => "DEBUG".to_string(),
+            Trap::Err => "ERR".to_string(),
+            Trap::Return => "RETURN".to_string(),
+            Trap::Signal(number) => match SIGNALS.iter().find(|&&(_, signal)| signal == *number) {
+                Some((name, _)) => format!("SIG{}", name),
+                None => number.to_string(),
+            },
+        }
+    }
+
+    /// The traps a function does not inherit; one it sets stays set after it returns
+    fn is_function_local(&self) -> bool {
+        matches!(self, Trap::Debug | Trap::Err | Trap::Return)
+    }
+}
+
+/// The command each trap runs; an empty command ignores the signal
+#[derive(Debug, Clone, Default)]
+pub struct Traps(BTreeMap<Trap, String>);
+
+impl Traps {
+    pub fn get(&self, trap: Trap) -> Option<&str> {
+        self.0.get(&trap).map(String::as_str)
+    }
+
+    /// The traps of a subshell: signals that were ignored stay ignored, and every
+    /// other trap is cleared
+    pub fn for_subshell(&self) -> Traps {
+        Traps(self.0.iter().filter(|(_, action)| action.is_empty()).map(|(&trap, action)| (trap, action.clone())).collect())
+    }
+
+    /// Takes out the traps a function does not inherit, to be given back by
+    /// `restore_function_traps` when it returns
+    pub fn take_function_traps(&mut self) -> Vec<(Trap, String)> {
+        let hidden: Vec<Trap> = self.0.keys().copied().filter(Trap::is_function_local).collect();
+        hidden.into_iter().filter_map(|trap| self.0.remove(&trap).map(|action| (trap, action))).collect()
+    }
+
+    /// Gives back the traps taken out for a function, except those it set itself
+    pub fn restore_function_traps(&mut self, hidden: Vec<(Trap, String)>) {
+        for (trap, action) in hidden {
+            self.0.entry(trap).or_insert(action);
+        }
+    }
+
+    /// Makes a command start with the signals ignored that have an empty trap. A
+    /// subshell's traps are not set on the process, so this is how they reach it
+    pub fn apply_ignored(&self, cmd: &mut Command) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let ignored: Vec<i32> = self
+                .0
+                .iter()
+                .filter_map(|(trap, action)| match trap {
+                    Trap::Signal(signal) if action.is_empty() => Some(*signal),
+                    _ => None,
+                })
+                .collect();
+            if ignored.is_empty() {
+                return;
+            }
+            // SAFETY: only async-signal-safe calls are made between fork and exec
+            unsafe {
+                cmd.pre_exec(move || {
+                    for &signal in &ignored {
+                        libc::signal(signal, libc::SIG_IGN);
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = cmd;
+    }
+
+    /// The signals with traps that run a command
+    fn caught_signals(&self) -> Vec<i32> {
+        self.0
+            .iter()
+            .filter_map(|(trap, action)| match trap {
+                Trap::Signal(signal) if !action.is_empty() => Some(*signal),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Shell {
+    /// `trap [-p] [[command] condition...]` sets the command run when a signal arrives
+    /// or the shell reaches a condition such as `EXIT`. An empty command ignores the
+    /// signal and `-` (or no command at all) resets it. Without a command, or with
+    /// `-p`, the traps are printed as commands that would set them again
+    pub fn trap(&mut self, args: &[String]) -> BuiltinResult {
+        let mut args = args;
+        let mut print = false;
+        while let Some(arg) = args.first() {
+            match arg.as_str() {
+                "-p" => print = true,
+                "--" => {
+                    args = &args[1..];
+                    break;
+                }
+                flag if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(BuiltinError::Usage(format!("{}: invalid option", flag)));
+                }
+                _ => break,
+            }
+            args = &args[1..];
+        }
+
+        if print || args.is_empty() {
+            let mut traps = Vec::new();
+            for spec in args {
+                match Trap::parse(spec) {
+                    Some(trap) => traps.push(trap),
+                    None => return Err(BuiltinError::InvalidArgument(format!("{}: invalid signal specification", spec))),
+                }
+            }
+            let mut out = self.stdout();
+            for (trap, action) in &self.traps.0 {
+                if traps.is_empty() || traps.contains(trap) {
+                    writeln!(out, "trap -- {} {}", quote(action), trap.name())?;
+                }
+            }
+            return Ok(0);
+        }
+
+        // A lone condition, or one starting with a number, resets rather than sets
+        let (action, specs) = match args {
+            [_] => (None, args),
+            [first, rest @ ..] if first == "-" => (None, rest),
+            [first, ..] if first.parse::<u32>().is_ok() => (None, args),
+            [first, rest @ ..] => (Some(first.clone()), rest),
+            [] => unreachable!(),
+        };
+        let mut status = 0;
+        for spec in specs {
+            let Some(trap) = Trap::parse(spec) else {
+                writeln!(self.stderr(), "trap: {}: invalid signal specification", spec)?;
+                status = 1;
+                continue;
+            };
+            // Subshells run in the shell's process, whose signals stay the shell's
+            if let (Trap::Signal(signal), false) = (trap, self.in_subshell) {
+                match &action {
+                    Some(action) => signals::trap_signal(signal, action.is_empty()),
+                    None => signals::untrap_signal(signal),
+                }
+            }
+            match &action {
+                Some(action) => self.traps.0.insert(trap, action.clone()),
+                None => self.traps.0.remove(&trap),
+            };
+        }
+        Ok(status)
+    }
+
+    /// Runs the command of a trap, if it has one. `$?` is left as it was, and the
+    /// trap's own commands do not set off the `DEBUG` and `ERR` traps
+    pub fn run_trap(&mut self, trap: Trap) {
+        let Some(action) = self.traps.get(trap).filter(|action| !action.is_empty()).map(str::to_string) else {
+            return;
+        };
+        let status = self.last_status;
+        // A `return` on its way out of a function waits until the trap has run
+        let flow = self.flow.take();
+        let running = std::mem::replace(&mut self.running_trap, true);
+        if let Err(e) = self.execute_command(&action) {
+            let _ = writeln!(self.stderr(), "trap: {}", e);
+        }
+        self.running_trap = running;
+        self.flow = flow.or(self.flow.take());
+        self.last_status = status;
+    }
+
+    /// Runs the traps of signals that arrived since they were last checked
+    pub fn run_pending_traps(&mut self) {
+        let caught = self.traps.caught_signals();
+        if caught.is_empty() {
+            return;
+        }
+        for signal in signals::take_pending(&caught) {
+            self.run_trap(Trap::Signal(signal));
+        }
+    }
+
+    /// Runs the `DEBUG` or `ERR` trap, unless a trap is already running
+    pub fn run_command_trap(&mut self, trap: Trap) {
+        if !self.running_trap {
+            self.run_trap(trap);
+        }
+    }
+}
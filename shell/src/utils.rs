@@ -1,6 +1,21 @@
 use std::io::{self, Write};
 use crate::shell_type::ShellType;
 
+/// Quotes `text` in single quotes so that the shell reads it back unchanged
+pub fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Quotes `text` only if it is empty or has characters the shell would treat specially
+pub fn quote_if_needed(text: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_./:,+=@%-".contains(c);
+    if !text.is_empty() && text.chars().all(plain) {
+        text.to_string()
+    } else {
+        quote(text)
+    }
+}
+
 pub fn detect_os() -> ShellType {
     if cfg!(windows) {
         ShellType::Windows
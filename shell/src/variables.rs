@@ -0,0 +1,840 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{self, Write};
+
+use crate::ast::{Assignment, WordPart};
+use crate::error::{BuiltinError, BuiltinResult, VariableError};
+use crate::parser;
+use crate::shell::Shell;
+use crate::utils::quote_if_needed;
+
+/// The value of a variable
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    /// Elements by index, which need not be contiguous
+    Indexed(BTreeMap<usize, String>),
+    Associative(BTreeMap<String, String>),
+}
+
+impl Value {
+    /// An indexed array of `values`, starting at index 0
+    pub fn list(values: impl IntoIterator<Item = String>) -> Value {
+        Value::Indexed(values.into_iter().enumerate().collect())
+    }
+
+    /// What `$NAME` expands to; for an array, its element 0
+    fn scalar(&self) -> Option<&str> {
+        match self {
+            Value::Scalar(value) => Some(value),
+            Value::Indexed(elements) => elements.get(&0).map(String::as_str),
+            Value::Associative(elements) => elements.get("0").map(String::as_str),
+        }
+    }
+
+    /// The elements in order of index or key; a scalar is an array of one element
+    pub fn values(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(value) => vec![value.clone()],
+            Value::Indexed(elements) => elements.values().cloned().collect(),
+            Value::Associative(elements) => elements.values().cloned().collect(),
+        }
+    }
+
+    /// The indices or keys of the elements, in order
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(_) => vec!["0".to_string()],
+            Value::Indexed(elements) => elements.keys().map(usize::to_string).collect(),
+            Value::Associative(elements) => elements.keys().cloned().collect(),
+        }
+    }
+
+    /// Each element with the index that slicing counts by; the elements of an
+    /// associative array are numbered in order
+    pub fn indexed_values(&self) -> Vec<(usize, String)> {
+        match self {
+            Value::Indexed(elements) => elements.iter().map(|(&index, value)| (index, value.clone())).collect(),
+            _ => self.values().into_iter().enumerate().collect(),
+        }
+    }
+
+    /// One past the highest index, which negative subscripts count back from
+    pub fn end(&self) -> usize {
+        match self {
+            Value::Scalar(_) => 1,
+            Value::Indexed(elements) => elements.keys().next_back().map_or(0, |&index| index + 1),
+            Value::Associative(_) => 0,
+        }
+    }
+
+    fn element(&self, key: &Key) -> Option<&str> {
+        match (self, key) {
+            (Value::Scalar(value), Key::Index(0)) => Some(value),
+            (Value::Indexed(elements), Key::Index(index)) => elements.get(index).map(String::as_str),
+            (Value::Associative(elements), Key::Name(name)) => elements.get(name).map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// How `declare -p` and `set` show the value
+    fn display(&self) -> String {
+        let elements: Vec<String> = match self {
+            Value::Scalar(value) => return double_quote(value),
+            Value::Indexed(elements) => {
+                elements.iter().map(|(index, value)| format!("[{}]={}", index, double_quote(value))).collect()
+            }
+            Value::Associative(elements) => elements
+                .iter()
+                .map(|(key, value)| {
+                    let plain = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    let key = if plain { key.clone() } else { double_quote(key) };
+                    format!("[{}]={}", key, double_quote(value))
+                })
+                .collect(),
+        };
+        format!("({})", elements.join(" "))
+    }
+}
+
+/// The element of an array that a subscript names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Key {
+    Index(usize),
+    Name(String),
+}
+
+/// The kind of array `declare -a` and `declare -A` make a variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayKind {
+    Indexed,
+    Associative,
+}
+
+/// A shell variable and its attributes
+#[derive(Debug, Clone, Default)]
+pub struct Variable {
+    /// `None` for a variable that `export`, `declare` or `local` named but gave no value
+    pub value: Option<Value>,
+    /// Passed in the environment of the commands the shell runs, unless it is an array
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+impl Variable {
+    fn array_kind(&self) -> Option<ArrayKind> {
+        match self.value {
+            Some(Value::Indexed(_)) => Some(ArrayKind::Indexed),
+            Some(Value::Associative(_)) => Some(ArrayKind::Associative),
+            _ => None,
+        }
+    }
+
+    /// How `declare -p` shows the variable
+    fn declaration(&self, name: &str) -> String {
+        let mut flags = String::new();
+        match self.array_kind() {
+            Some(ArrayKind::Indexed) => flags.push('a'),
+            Some(ArrayKind::Associative) => flags.push('A'),
+            None => {}
+        }
+        if self.readonly {
+            flags.push('r');
+        }
+        if self.exported {
+            flags.push('x');
+        }
+        let flags = if flags.is_empty() { "-".to_string() } else { flags };
+        match &self.value {
+            Some(value) => format!("declare -{} {}={}", flags, name, value.display()),
+            None => format!("declare -{} {}", flags, name),
+        }
+    }
+}
+
+/// Quotes `text` in double quotes, escaping what would still be special inside them
+fn double_quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits `NAME` or `NAME[index]` into the name and subscript, or returns `None` if
+/// `text` is neither
+fn split_name(text: &str) -> Option<(&str, Option<&str>)> {
+    let (name, index) = match text.split_once('[') {
+        Some((name, rest)) => (name, Some(rest.strip_suffix(']').filter(|index| !index.is_empty())?)),
+        None => (text, None),
+    };
+    parser::is_valid_name(name).then_some((name, index))
+}
+
+/// The shell's variables by name
+#[derive(Debug, Clone, Default)]
+pub struct Variables(HashMap<String, Variable>);
+
+impl Variables {
+    /// The variables of the environment the shell started in, all exported
+    pub fn from_env() -> Variables {
+        Variables(
+            env::vars()
+                .map(|(name, value)| {
+                    (name, Variable { value: Some(Value::Scalar(value)), exported: true, readonly: false })
+                })
+                .collect(),
+        )
+    }
+
+    /// The value of `$NAME`, or `None` if it is unset
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.value(name)?.scalar()
+    }
+
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)?.value.as_ref()
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.0.get(name)
+    }
+
+    pub fn element(&self, name: &str, key: &Key) -> Option<&str> {
+        self.value(name)?.element(key)
+    }
+
+    pub fn is_associative(&self, name: &str) -> bool {
+        matches!(self.value(name), Some(Value::Associative(_)))
+    }
+
+    /// The element that `$NAME` stands for
+    fn scalar_key(&self, name: &str) -> Key {
+        match self.is_associative(name) {
+            true => Key::Name("0".to_string()),
+            false => Key::Index(0),
+        }
+    }
+
+    /// Sets `$NAME`, which for an array is its element 0, keeping the attributes
+    pub fn set(&mut self, name: &str, value: impl Into<String>) -> Result<(), VariableError> {
+        self.set_element(name, self.scalar_key(name), value.into(), false)
+    }
+
+    /// Replaces the whole value of a variable, keeping its attributes
+    pub fn set_value(&mut self, name: &str, value: Value) -> Result<(), VariableError> {
+        let variable = self.declare(name);
+        if variable.readonly {
+            return Err(VariableError::Readonly(name.to_string()));
+        }
+        variable.value = Some(value);
+        Ok(())
+    }
+
+    /// Sets one element of an array, or appends to it. A scalar is element 0 of an
+    /// array, and becomes one when another element is set
+    pub fn set_element(&mut self, name: &str, key: Key, value: String, append: bool) -> Result<(), VariableError> {
+        let variable = self.declare(name);
+        if variable.readonly {
+            return Err(VariableError::Readonly(name.to_string()));
+        }
+        let element = |old: Option<String>| match old {
+            Some(old) if append => old + &value,
+            _ => value,
+        };
+        let updated = match (variable.value.take(), key) {
+            (Some(Value::Scalar(old)), Key::Index(0)) => Value::Scalar(element(Some(old))),
+            (None, Key::Index(0)) => Value::Scalar(element(None)),
+            (Some(Value::Scalar(old)), Key::Index(index)) => {
+                Value::Indexed(BTreeMap::from([(0, old), (index, element(None))]))
+            }
+            (None, Key::Index(index)) => Value::Indexed(BTreeMap::from([(index, element(None))])),
+            (Some(Value::Indexed(mut elements)), Key::Index(index)) => {
+                let old = elements.remove(&index);
+                elements.insert(index, element(old));
+                Value::Indexed(elements)
+            }
+            (None, Key::Name(key)) => Value::Associative(BTreeMap::from([(key, element(None))])),
+            (Some(Value::Associative(mut elements)), Key::Name(key)) => {
+                let old = elements.remove(&key);
+                elements.insert(key, element(old));
+                Value::Associative(elements)
+            }
+            (current, _) => {
+                variable.value = current;
+                return Err(VariableError::BadSubscript(name.to_string()));
+            }
+        };
+        variable.value = Some(updated);
+        Ok(())
+    }
+
+    /// Makes a variable an array of `kind`; a scalar becomes its element 0
+    pub fn make_array(&mut self, name: &str, kind: ArrayKind) -> Result<(), VariableError> {
+        let variable = self.declare(name);
+        let array = match (variable.value.take(), kind) {
+            (None, ArrayKind::Indexed) => Value::Indexed(BTreeMap::new()),
+            (None, ArrayKind::Associative) => Value::Associative(BTreeMap::new()),
+            (Some(Value::Scalar(value)), ArrayKind::Indexed) => Value::list([value]),
+            (Some(Value::Scalar(value)), ArrayKind::Associative) => {
+                Value::Associative(BTreeMap::from([("0".to_string(), value)]))
+            }
+            (Some(array @ Value::Indexed(_)), ArrayKind::Indexed)
+            | (Some(array @ Value::Associative(_)), ArrayKind::Associative) => array,
+            (current, _) => {
+                variable.value = current;
+                return Err(match kind {
+                    ArrayKind::Indexed => VariableError::ToIndexed(name.to_string()),
+                    ArrayKind::Associative => VariableError::ToAssociative(name.to_string()),
+                });
+            }
+        };
+        variable.value = Some(array);
+        Ok(())
+    }
+
+    /// Removes a variable along with its attributes
+    pub fn unset(&mut self, name: &str) -> Result<(), VariableError> {
+        if self.0.get(name).is_some_and(|variable| variable.readonly) {
+            return Err(VariableError::Readonly(name.to_string()));
+        }
+        self.0.remove(name);
+        Ok(())
+    }
+
+    /// Removes one element of an array; element 0 of a scalar is the variable itself
+    pub fn unset_element(&mut self, name: &str, key: &Key) -> Result<(), VariableError> {
+        let Some(variable) = self.0.get_mut(name) else {
+            return Ok(());
+        };
+        if variable.readonly {
+            return Err(VariableError::Readonly(name.to_string()));
+        }
+        match (&mut variable.value, key) {
+            (Some(Value::Scalar(_)), Key::Index(0)) => {
+                self.0.remove(name);
+            }
+            (Some(Value::Indexed(elements)), Key::Index(index)) => {
+                elements.remove(index);
+            }
+            (Some(Value::Associative(elements)), Key::Name(key)) => {
+                elements.remove(key);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The variable called `name`, created without a value if there is none
+    pub fn declare(&mut self, name: &str) -> &mut Variable {
+        self.0.entry(name.to_string()).or_default()
+    }
+
+    /// Puts back a variable as it was saved, readonly or not; `None` unsets it
+    pub fn restore(&mut self, name: &str, variable: Option<Variable>) {
+        match variable {
+            Some(variable) => self.0.insert(name.to_string(), variable),
+            None => self.0.remove(name),
+        };
+    }
+
+    /// Fails for the first of `names` that is readonly
+    pub fn check_assignable<'a>(&self, mut names: impl Iterator<Item = &'a str>) -> Result<(), VariableError> {
+        match names.find(|name| self.0.get(*name).is_some_and(|variable| variable.readonly)) {
+            Some(name) => Err(VariableError::Readonly(name.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Name and value of each exported variable that has a value; arrays are never
+    /// exported
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().filter(|(_, variable)| variable.exported).filter_map(|(name, variable)| {
+            match &variable.value {
+                Some(Value::Scalar(value)) => Some((name.as_str(), value.as_str())),
+                _ => None,
+            }
+        })
+    }
+
+    /// Every variable, sorted by name
+    pub fn sorted(&self) -> Vec<(&str, &Variable)> {
+        let mut variables: Vec<(&str, &Variable)> = self.0.iter().map(|(name, variable)| (name.as_str(), variable)).collect();
+        variables.sort_by_key(|&(name, _)| name);
+        variables
+    }
+}
+
+/// Attributes that `declare`, `export`, `readonly` and `local` give the variables they name
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Attributes {
+    /// Marks the variables exported, or with `Some(false)` takes the mark off
+    pub export: Option<bool>,
+    pub readonly: bool,
+    pub array: Option<ArrayKind>,
+    /// Makes the variables local to the running function
+    pub local: bool,
+}
+
+/// Option letters, each with whether it came after `-` rather than `+`
+type Options = Vec<(char, bool)>;
+
+/// Splits the leading options off `args`. Only `declare` and `local` take options
+/// starting with `+`
+fn split_options<'a>(args: &'a [String], allowed: &str, plus: bool) -> Result<(Options, &'a [String]), BuiltinError> {
+    let mut options = Vec::new();
+    let mut rest = args;
+    while let Some(arg) = rest.first() {
+        if arg == "--" {
+            rest = &rest[1..];
+            break;
+        }
+        let on = arg.starts_with('-');
+        if arg.len() < 2 || !(on || (plus && arg.starts_with('+'))) {
+            break;
+        }
+        for letter in arg[1..].chars() {
+            if !allowed.contains(letter) {
+                return Err(BuiltinError::Usage(format!("{}{}: invalid option", &arg[..1], letter)));
+            }
+            options.push((letter, on));
+        }
+        rest = &rest[1..];
+    }
+    Ok((options, rest))
+}
+
+/// Reads the options of `declare` and `local` into the attributes they give, and
+/// whether `-p` asks for the variables to be printed
+pub fn declaration_options(args: &[String], local: bool) -> Result<(Attributes, bool, &[String]), BuiltinError> {
+    let (options, names) = split_options(args, "aAprx", true)?;
+    let mut attributes = Attributes { local, ..Attributes::default() };
+    let mut print = false;
+    for (letter, on) in options {
+        match (letter, on) {
+            ('p', _) => print = true,
+            ('x', export) => attributes.export = Some(export),
+            ('r', true) => attributes.readonly = true,
+            ('a', true) => attributes.array = Some(ArrayKind::Indexed),
+            ('A', true) => attributes.array = Some(ArrayKind::Associative),
+            ('r', false) => return Err(BuiltinError::Usage("+r: cannot remove the readonly attribute".to_string())),
+            (_, _) => return Err(BuiltinError::Usage(format!("+{}: cannot destroy array variables this way", letter))),
+        }
+    }
+    Ok((attributes, print, names))
+}
+
+impl Shell {
+    /// Sets or declares each `name[=value]` of `args` with the given attributes,
+    /// reporting bad names and readonly variables as `builtin` and going on with the
+    /// rest. A value in parentheses is the list of an array
+    pub fn declare_variables(&mut self, builtin: &str, args: &[String], attributes: Attributes) -> io::Result<i32> {
+        let mut status = 0;
+        for arg in args {
+            let (target, value) = match arg.split_once('=') {
+                Some((target, value)) => (target, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            let (target, append) = match target.strip_suffix('+') {
+                Some(target) if value.is_some() => (target, true),
+                _ => (target, false),
+            };
+            let Some((name, index)) = split_name(target) else {
+                writeln!(self.stderr(), "{}: `{}': not a valid identifier", builtin, arg)?;
+                status = 1;
+                continue;
+            };
+            let previous = self.variables.variable(name).cloned();
+            if previous.as_ref().is_some_and(|variable| variable.readonly) && (value.is_some() || attributes.local) {
+                writeln!(self.stderr(), "{}: {}", builtin, VariableError::Readonly(name.to_string()))?;
+                status = 1;
+                continue;
+            }
+            if attributes.local {
+                let Some(scope) = self.local_scopes.last_mut() else {
+                    return Err(io::Error::other(format!("{}: can only be used in a function", builtin)));
+                };
+                if !scope.contains_key(name) {
+                    scope.insert(name.to_string(), previous.clone());
+                    // A local copy starts out unset, but stays exported
+                    let exported = previous.is_some_and(|variable| variable.exported);
+                    self.variables.restore(name, Some(Variable { exported, ..Variable::default() }));
+                }
+            }
+
+            // The kind of array is settled first, as it decides how the value is taken
+            let mut result = match attributes.array {
+                Some(kind) => self.variables.make_array(name, kind),
+                None => Ok(()),
+            };
+            if let (Ok(()), Some(value)) = (&result, value) {
+                result = match value.strip_prefix('(').and_then(|list| list.strip_suffix(')')) {
+                    Some(list) if index.is_none() => {
+                        let elements = self.expand_array_text(list)?;
+                        self.assign_array(name, elements, append)
+                    }
+                    _ => self.assign_scalar(name, index, value.to_string(), append),
+                };
+            }
+            if let Err(e) = result {
+                writeln!(self.stderr(), "{}: {}", builtin, e)?;
+                status = 1;
+                continue;
+            }
+
+            let variable = self.variables.declare(name);
+            if let Some(export) = attributes.export {
+                variable.exported = export;
+            }
+            variable.readonly |= attributes.readonly;
+        }
+        Ok(status)
+    }
+
+    /// Carries out a `NAME=value`, `NAME+=value`, `NAME[index]=value` or `NAME=(...)`
+    /// assignment. Expansion errors abort the command; a readonly variable or a bad
+    /// subscript is returned to be reported
+    pub fn assign(&mut self, assignment: &Assignment) -> io::Result<Result<(), VariableError>> {
+        let index = match &assignment.index {
+            Some(index) => Some(self.expand_subscript(index)?),
+            None => None,
+        };
+        let operator = if assignment.append { "+=" } else { "=" };
+        if let [WordPart::Array(elements)] = assignment.value.parts.as_slice() {
+            if let Some(index) = index {
+                return Ok(Err(VariableError::ListToElement(format!("{}[{}]", assignment.name, index))));
+            }
+            let elements = self.expand_array(elements)?;
+            let listed: Vec<String> = elements
+                .iter()
+                .map(|(index, value)| match index {
+                    Some(index) => format!("[{}]={}", index, quote_if_needed(value)),
+                    None => quote_if_needed(value),
+                })
+                .collect();
+            self.trace_command([format!("{}{}({})", assignment.name, operator, listed.join(" "))]);
+            return Ok(self.assign_array(&assignment.name, elements, assignment.append));
+        }
+        let value = self.expand_assignment_value(&assignment.value)?;
+        let subscript = index.as_ref().map(|index| format!("[{}]", index)).unwrap_or_default();
+        self.trace_command([format!("{}{}{}{}", assignment.name, subscript, operator, quote_if_needed(&value))]);
+        Ok(self.assign_scalar(&assignment.name, index.as_deref(), value, assignment.append))
+    }
+
+    /// Sets `$NAME` or the element `index` names, or appends to it
+    pub fn assign_scalar(&mut self, name: &str, index: Option<&str>, value: String, append: bool) -> Result<(), VariableError> {
+        let key = match index {
+            Some(index) => self.element_key(name, index)?,
+            None => self.variables.scalar_key(name),
+        };
+        self.variables.set_element(name, key, value, append)
+    }
+
+    /// Gives an array the elements of a `(...)` list, after the ones it has with
+    /// `append`. Elements with a subscript go where it says and the others follow on
+    fn assign_array(&mut self, name: &str, elements: Vec<(Option<String>, String)>, append: bool) -> Result<(), VariableError> {
+        self.variables.check_assignable(std::iter::once(name))?;
+        if let Some(Value::Associative(existing)) = self.variables.value(name) {
+            let mut array = if append { existing.clone() } else { BTreeMap::new() };
+            for (key, element) in elements {
+                let Some(key) = key else {
+                    return Err(VariableError::NeedsKey(format!("{}: {}", name, element)));
+                };
+                array.insert(key, element);
+            }
+            return self.variables.set_value(name, Value::Associative(array));
+        }
+
+        let mut array = match self.variables.value(name) {
+            Some(Value::Indexed(existing)) if append => existing.clone(),
+            Some(Value::Scalar(existing)) if append => BTreeMap::from([(0, existing.clone())]),
+            _ => BTreeMap::new(),
+        };
+        let mut next = array.keys().next_back().map_or(0, |&index| index + 1);
+        for (subscript, element) in elements {
+            let index = match subscript {
+                Some(subscript) => {
+                    let end = array.keys().next_back().map_or(0, |&index| index + 1);
+                    self.array_index(name, &subscript, end)?
+                }
+                None => next,
+            };
+            array.insert(index, element);
+            next = index + 1;
+        }
+        self.variables.set_value(name, Value::Indexed(array))
+    }
+
+    /// The element of `name` that `subscript` names: a key of an associative array, or
+    /// else an arithmetic expression for an index
+    pub fn element_key(&mut self, name: &str, subscript: &str) -> Result<Key, VariableError> {
+        if self.variables.is_associative(name) {
+            return Ok(Key::Name(subscript.to_string()));
+        }
+        let end = self.variables.value(name).map_or(0, Value::end);
+        self.array_index(name, subscript, end).map(Key::Index)
+    }
+
+    /// Evaluates an index; a negative one counts back from `end`
+    fn array_index(&mut self, name: &str, subscript: &str, end: usize) -> Result<usize, VariableError> {
+        let index = self
+            .evaluate_arithmetic(subscript)
+            .map_err(|e| VariableError::Subscript(format!("{}: {}", subscript.trim(), e)))?;
+        let index = if index < 0 { index + end as i64 } else { index };
+        usize::try_from(index).map_err(|_| VariableError::BadSubscript(format!("{}[{}]", name, subscript)))
+    }
+
+    /// Expands the list of an array given to `declare` as text, as in `declare -a a='(1 2)'`
+    fn expand_array_text(&mut self, list: &str) -> io::Result<Vec<(Option<String>, String)>> {
+        let words = parser::parse_words(list).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.expand_array(&words)
+    }
+
+    /// Lists the variables that have values as `name=value`, quoted where needed
+    pub fn print_variables(&self) -> io::Result<()> {
+        let mut out = self.stdout();
+        for (name, variable) in self.variables.sorted() {
+            match &variable.value {
+                Some(Value::Scalar(value)) => writeln!(out, "{}={}", name, quote_if_needed(value))?,
+                Some(array) => writeln!(out, "{}={}", name, array.display())?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the variables that `keep` selects as `declare` commands
+    fn print_declarations(&self, keep: impl Fn(&Variable) -> bool) -> io::Result<()> {
+        let mut out = self.stdout();
+        for (name, variable) in self.variables.sorted() {
+            if keep(variable) {
+                writeln!(out, "{}", variable.declaration(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the variables of a command's prefix assignments their values, exported,
+    /// while the command runs. Returns what they replaced, for `restore_variables`
+    pub fn assign_temporary(&mut self, assignments: &[(String, String)]) -> Result<Vec<(String, Option<Variable>)>, VariableError> {
+        self.variables.check_assignable(assignments.iter().map(|(name, _)| name.as_str()))?;
+        let mut saved = Vec::new();
+        for (name, value) in assignments {
+            saved.push((name.clone(), self.variables.variable(name).cloned()));
+            let variable = Variable { value: Some(Value::Scalar(value.clone())), exported: true, readonly: false };
+            self.variables.restore(name, Some(variable));
+        }
+        Ok(saved)
+    }
+
+    pub fn restore_variables(&mut self, saved: Vec<(String, Option<Variable>)>) {
+        for (name, variable) in saved.into_iter().rev() {
+            self.variables.restore(&name, variable);
+        }
+    }
+
+    /// `export [-n] [-p] [name[=value]...]` marks variables to be passed to the
+    /// commands the shell runs, setting their values when given; `-n` takes the mark
+    /// off. Without names the exported variables are listed
+    pub fn export(&mut self, args: &[String]) -> BuiltinResult {
+        let (options, names) = split_options(args, "np", false)?;
+        if names.is_empty() {
+            self.print_declarations(|variable| variable.exported)?;
+            return Ok(0);
+        }
+        let export = !options.contains(&('n', true));
+        Ok(self.declare_variables("export", names, Attributes { export: Some(export), ..Attributes::default() })?)
+    }
+
+    /// `readonly [-p] [name[=value]...]` stops variables from being changed or unset.
+    /// Without names the readonly variables are listed
+    pub fn readonly(&mut self, args: &[String]) -> BuiltinResult {
+        let (_, names) = split_options(args, "p", false)?;
+        if names.is_empty() {
+            self.print_declarations(|variable| variable.readonly)?;
+            return Ok(0);
+        }
+        Ok(self.declare_variables("readonly", names, Attributes { readonly: true, ..Attributes::default() })?)
+    }
+
+    /// `declare [-p] [-aArx] [+x] [name[=value]...]` sets variables and their
+    /// attributes; inside a function they are local to it. `-p`, or no names, prints
+    /// the variables as `declare` commands
+    pub fn declare(&mut self, args: &[String]) -> BuiltinResult {
+        let (attributes, print, names) = declaration_options(args, !self.local_scopes.is_empty())?;
+        if names.is_empty() {
+            let exported = attributes.export == Some(true);
+            self.print_declarations(|variable| {
+                (!exported || variable.exported)
+                    && (!attributes.readonly || variable.readonly)
+                    && attributes.array.map_or(true, |kind| variable.array_kind() == Some(kind))
+            })?;
+            return Ok(0);
+        }
+        if print {
+            let mut status = 0;
+            for name in names {
+                match self.variables.variable(name) {
+                    Some(variable) => writeln!(self.stdout(), "{}", variable.declaration(name))?,
+                    None => {
+                        writeln!(self.stderr(), "declare: {}: not found", name)?;
+                        status = 1;
+                    }
+                }
+            }
+            return Ok(status);
+        }
+        Ok(self.declare_variables("declare", names, attributes)?)
+    }
+
+    /// `unset [-f|-v] name...` removes variables, or with `-f` functions; `name[index]`
+    /// removes one element of an array. Without an option a name that is not a
+    /// variable is taken to be a function
+    pub fn unset(&mut self, args: &[String]) -> BuiltinResult {
+        let (options, names) = split_options(args, "fv", false)?;
+        let functions = options.iter().any(|&(letter, _)| letter == 'f');
+        let variables = options.iter().any(|&(letter, _)| letter == 'v');
+        let mut status = 0;
+        for arg in names {
+            if functions && !variables {
+                self.functions.remove(arg);
+                continue;
+            }
+            let Some((name, index)) = split_name(arg) else {
+                writeln!(self.stderr(), "unset: `{}': not a valid identifier", arg)?;
+                status = 1;
+                continue;
+            };
+            if let Some(index) = index {
+                let result = self.element_key(name, index).and_then(|key| self.variables.unset_element(name, &key));
+                if let Err(e) = result {
+                    writeln!(self.stderr(), "unset: {}", e)?;
+                    status = 1;
+                }
+                continue;
+            }
+            if self.variables.variable(name).is_none() && !variables {
+                self.functions.remove(name);
+                continue;
+            }
+            if self.variables.unset(name).is_err() {
+                writeln!(self.stderr(), "unset: {}: cannot unset: readonly variable", name)?;
+                status = 1;
+            }
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(shell: &mut Shell, line: &str) {
+        shell.execute_command(line).unwrap();
+    }
+
+    fn values(shell: &Shell, name: &str) -> Vec<String> {
+        shell.variables.value(name).map(Value::values).unwrap_or_default()
+    }
+
+    fn keys(shell: &Shell, name: &str) -> Vec<String> {
+        shell.variables.value(name).map(Value::keys).unwrap_or_default()
+    }
+
+    #[test]
+    fn sparse_indices() {
+        let mut variables = Variables::default();
+        variables.set_element("a", Key::Index(5), "x".to_string(), false).unwrap();
+        variables.set_element("a", Key::Index(2), "y".to_string(), false).unwrap();
+        assert_eq!(variables.value("a").unwrap().keys(), ["2", "5"]);
+        assert_eq!(variables.value("a").unwrap().end(), 6);
+        assert_eq!(variables.element("a", &Key::Index(3)), None);
+        assert_eq!(variables.get("a"), None);
+
+        let mut shell = Shell::for_tests();
+        run(&mut shell, "a=(x [5]=y z)");
+        assert_eq!(keys(&shell, "a"), ["0", "5", "6"]);
+        assert_eq!(values(&shell, "a"), ["x", "y", "z"]);
+    }
+
+    #[test]
+    fn negative_subscripts() {
+        let mut shell = Shell::for_tests();
+        run(&mut shell, "a=(x y z)");
+        shell.assign_scalar("a", Some("-1"), "Z".to_string(), false).unwrap();
+        assert_eq!(values(&shell, "a"), ["x", "y", "Z"]);
+        assert_eq!(shell.element_key("a", "-3").unwrap(), Key::Index(0));
+        assert!(matches!(shell.element_key("a", "-4"), Err(VariableError::BadSubscript(_))));
+
+        // They count back from one past the highest index, not the number of elements
+        run(&mut shell, "b=([5]=x)");
+        assert_eq!(shell.element_key("b", "-1").unwrap(), Key::Index(5));
+    }
+
+    #[test]
+    fn appending_to_scalars() {
+        let mut shell = Shell::for_tests();
+        run(&mut shell, "s=ab; s+=cd");
+        assert_eq!(shell.variables.get("s"), Some("abcd"));
+        shell.assign_scalar("s", None, "ef".to_string(), true).unwrap();
+        assert_eq!(shell.variables.get("s"), Some("abcdef"));
+        run(&mut shell, "s+=(g)");
+        assert_eq!(shell.variables.value("s"), Some(&Value::list(["abcdef".to_string(), "g".to_string()])));
+    }
+
+    #[test]
+    fn appending_to_arrays() {
+        let mut shell = Shell::for_tests();
+        run(&mut shell, "a=(x); a+=(y z); a+=w; a[1]+=!");
+        assert_eq!(values(&shell, "a"), ["xw", "y!", "z"]);
+        run(&mut shell, "b=([3]=x); b+=(y)");
+        assert_eq!(keys(&shell, "b"), ["3", "4"]);
+
+        run(&mut shell, "declare -A m=([k]=v); m[k]+=w; m+=([j]=u)");
+        assert_eq!(keys(&shell, "m"), ["j", "k"]);
+        assert_eq!(values(&shell, "m"), ["u", "vw"]);
+    }
+
+    #[test]
+    fn converting_between_array_kinds() {
+        let mut variables = Variables::default();
+        variables.set_value("a", Value::list(["x".to_string()])).unwrap();
+        variables.set_value("m", Value::Associative(BTreeMap::from([("k".to_string(), "v".to_string())]))).unwrap();
+
+        let error = variables.make_array("a", ArrayKind::Associative);
+        assert!(matches!(error, Err(VariableError::ToAssociative(_))));
+        assert_eq!(variables.value("a"), Some(&Value::list(["x".to_string()])));
+        let error = variables.make_array("m", ArrayKind::Indexed);
+        assert!(matches!(error, Err(VariableError::ToIndexed(_))));
+        assert!(variables.is_associative("m"));
+
+        let error = variables.set_element("a", Key::Name("k".to_string()), "y".to_string(), false);
+        assert!(matches!(error, Err(VariableError::BadSubscript(_))));
+        let error = variables.set_element("m", Key::Index(1), "y".to_string(), false);
+        assert!(matches!(error, Err(VariableError::BadSubscript(_))));
+        assert_eq!(variables.value("a"), Some(&Value::list(["x".to_string()])));
+
+        // A scalar can become either kind
+        variables.set("s", "v").unwrap();
+        variables.make_array("s", ArrayKind::Associative).unwrap();
+        assert_eq!(variables.element("s", &Key::Name("0".to_string())), Some("v"));
+    }
+
+    #[test]
+    fn arrays_are_never_exported() {
+        let mut variables = Variables::default();
+        variables.set("s", "v").unwrap();
+        variables.set_value("a", Value::list(["x".to_string()])).unwrap();
+        variables.set_value("m", Value::Associative(BTreeMap::new())).unwrap();
+        variables.declare("u");
+        for name in ["s", "a", "m", "u"] {
+            variables.declare(name).exported = true;
+        }
+        assert_eq!(variables.exported().collect::<Vec<_>>(), [("s", "v")]);
+    }
+}